@@ -0,0 +1,297 @@
+use std::fs::{create_dir_all, read_to_string, remove_dir, write};
+use std::path::{Path, PathBuf};
+use nix::unistd::Pid;
+use toml::Table;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CgroupLimits {
+  pub cpu_weight: Option<u32>,
+  pub cpu_max: Option<String>,
+  pub memory_high: Option<String>,
+  pub memory_max: Option<String>,
+  pub io_weight: Option<u32>,
+  pub pids_max: Option<String>
+}
+
+impl CgroupLimits {
+  pub fn is_empty(&self) -> bool {
+    *self == CgroupLimits::default()
+  }
+
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    match key {
+      "cpu.weight" => self.cpu_weight = Some(parse_weight(key, value)?),
+      "cpu.max" => self.cpu_max = Some(parse_cpu_max(value)?),
+      "memory.high" => self.memory_high = Some(parse_bytes(key, value)?),
+      "memory.max" => self.memory_max = Some(parse_bytes(key, value)?),
+      "io.weight" => self.io_weight = Some(parse_weight(key, value)?),
+      "pids.max" => self.pids_max = Some(parse_max(key, value)?),
+      _ => return Err(format!("Unknown cgroup setting {}", key))
+    }
+    Ok(())
+  }
+
+  pub fn settings(&self) -> Vec<(&'static str, String)> {
+    let mut settings = Vec::new();
+    if let Some(weight) = self.cpu_weight {settings.push(("cpu.weight", weight.to_string()))}
+    if let Some(max) = &self.cpu_max {settings.push(("cpu.max", max.clone()))}
+    if let Some(high) = &self.memory_high {settings.push(("memory.high", high.clone()))}
+    if let Some(max) = &self.memory_max {settings.push(("memory.max", max.clone()))}
+    if let Some(weight) = self.io_weight {settings.push(("io.weight", weight.to_string()))}
+    if let Some(max) = &self.pids_max {settings.push(("pids.max", max.clone()))}
+    settings
+  }
+
+  fn files(&self) -> Vec<(&'static str, String)> {
+    self.settings().into_iter()
+      .map(|(file, value)| if file == "io.weight" {(file, format!("default {}", value))} else {(file, value)}).collect()
+  }
+}
+
+impl std::fmt::Display for CgroupLimits {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let settings: Vec<String> = self.settings().iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    f.write_str(&settings.join(", "))
+  }
+}
+
+fn parse_weight(key: &str, value: &str) -> Result<u32, String> {
+  let weight = value.parse::<u32>().map_err(|e| format!("{}: {}", key, e))?;
+  if !(1..=10000).contains(&weight) {return Err(format!("{} must be between 1 and 10000", key))}
+  Ok(weight)
+}
+
+fn parse_max(key: &str, value: &str) -> Result<String, String> {
+  if value == "max" {return Ok(value.to_string())}
+  value.parse::<u64>().map(|n| n.to_string()).map_err(|e| format!("{}: {}", key, e))
+}
+
+fn parse_cpu_max(value: &str) -> Result<String, String> {
+  let mut parts = value.split_whitespace();
+  let quota = parse_max("cpu.max", parts.next().unwrap_or(""))?;
+  match (parts.next(), parts.next()) {
+    (None, _) => Ok(quota),
+    (Some(period), None) => Ok(format!("{} {}", quota, parse_max("cpu.max", period)?)),
+    _ => Err("cpu.max expects \"<quota|max> [period]\"".to_string())
+  }
+}
+
+fn parse_bytes(key: &str, value: &str) -> Result<String, String> {
+  if value == "max" {return Ok(value.to_string())}
+  crate::parse_size(value).map(|b| b.to_string()).map_err(|e| format!("{}: {}", key, e))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CgroupSettings {
+  root: Option<PathBuf>,
+  parent: Option<String>
+}
+
+impl CgroupSettings {
+  pub fn update(&mut self, table: &Table) -> Result<(), String> {
+    for (key, value) in table {
+      match key.as_str() {
+        "root" => {
+          let root = value.as_str().filter(|root| root.starts_with('/')).ok_or("cgroup.root must be an absolute path")?;
+          self.root = Some(PathBuf::from(root));
+        }
+        "parent" => {
+          let parent = value.as_str().ok_or("cgroup.parent must be a string")?;
+          if parent.split('/').any(|component| component == "..") {return Err("cgroup.parent must not contain ..".to_string())}
+          self.parent = Some(parent.to_string());
+        }
+        _ => return Err(format!("Unknown key cgroup.{}", key))
+      }
+    }
+    Ok(())
+  }
+
+  pub fn root(&self) -> PathBuf {
+    std::env::var_os("POLITE_CGROUP_ROOT").map(PathBuf::from).or_else(|| self.root.clone()).unwrap_or_else(|| PathBuf::from("/sys/fs/cgroup"))
+  }
+
+  pub fn parent(&self) -> String {
+    std::env::var("POLITE_CGROUP_PARENT").ok().or_else(|| self.parent.clone()).unwrap_or_else(|| "polite".to_string())
+  }
+}
+
+fn enable_controllers(dir: &Path, controllers: &[&str]) -> Result<(), String> {
+  let enabled = read_to_string(dir.join("cgroup.subtree_control")).unwrap_or_default();
+  let missing: Vec<String> = controllers.iter().filter(|controller| !enabled.split_whitespace().any(|enabled| enabled == **controller))
+    .map(|controller| format!("+{}", controller)).collect();
+  if missing.is_empty() {return Ok(())}
+  write(dir.join("cgroup.subtree_control"), missing.join(" "))
+    .map_err(|e| format!("Cgroup error: enabling controllers in {}: {}", dir.display(), e))
+}
+
+#[derive(Debug)]
+pub struct Cgroup {
+  root: PathBuf,
+  name: String
+}
+
+impl Cgroup {
+  pub fn create(root: &Path, parent: &str, leaf: &str, limits: &CgroupLimits) -> Result<Cgroup, String> {
+    let name = match parent.trim_matches('/') {
+      "" => format!("/{}", leaf),
+      parent => format!("/{}/{}", parent, leaf)
+    };
+    let cgroup = Cgroup {root: root.to_path_buf(), name};
+    create_dir_all(cgroup.path()).map_err(|e| format!("Cgroup error: {}: {}", cgroup.path().display(), e))?;
+    let files = limits.files();
+    let mut controllers: Vec<&str> = files.iter().map(|(file, _)| file.split('.').next().unwrap_or(file)).collect();
+    controllers.dedup();
+    let mut dir = root.to_path_buf();
+    for component in parent.split('/').filter(|c| !c.is_empty()) {
+      enable_controllers(&dir, &controllers)?;
+      dir.push(component);
+    }
+    enable_controllers(&dir, &controllers)?;
+    cgroup.set_limits(limits)?;
+    Ok(cgroup)
+  }
+
+  pub fn set_limits(&self, limits: &CgroupLimits) -> Result<(), String> {
+    for (file, value) in limits.files() {
+      write(self.path().join(file), &value).map_err(|e| format!("Cgroup error: {}={}: {}", file, value, e))?;
+    }
+    Ok(())
+  }
+
+  pub fn for_pid(root: &Path, pid: Pid) -> Result<Cgroup, String> {
+    let membership = read_to_string(format!("/proc/{}/cgroup", pid)).map_err(|e| format!("Get cgroup error: {}", e))?;
+    let name = membership.lines().find_map(|line| line.strip_prefix("0::"))
+      .ok_or_else(|| format!("PID {} is not in a cgroup v2 hierarchy", pid))?;
+    Ok(Cgroup {root: root.to_path_buf(), name: name.to_string()})
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn path(&self) -> PathBuf {
+    self.root.join(self.name.trim_start_matches('/'))
+  }
+
+  pub fn add_process(&self, pid: Pid) -> Result<(), String> {
+    write(self.path().join("cgroup.procs"), pid.to_string()).map_err(|e| format!("Cgroup error: moving PID {}: {}", pid, e))
+  }
+
+  pub fn procs(&self) -> Vec<Pid> {
+    read_to_string(self.path().join("cgroup.procs")).unwrap_or_default().lines()
+      .filter_map(|line| line.trim().parse().ok()).map(Pid::from_raw).collect()
+  }
+
+  pub fn remove(&self) {
+    if let Err(e) = remove_dir(self.path()) {eprintln!("Cgroup error: removing {}: {}", self.path().display(), e)}
+  }
+
+  pub fn freeze(&self, frozen: bool) -> Result<(), String> {
+    write(self.path().join("cgroup.freeze"), if frozen {"1"} else {"0"}).map_err(|e| format!("Cgroup error: freezing: {}", e))
+  }
+
+  pub fn usage(&self) -> Vec<(String, String)> {
+    let mut usage = Vec::new();
+    for file in ["memory.current", "memory.peak", "pids.current"] {
+      if let Ok(value) = read_to_string(self.path().join(file)) {usage.push((file.to_string(), value.trim().to_string()))}
+    }
+    if let Ok(stat) = read_to_string(self.path().join("cpu.stat")) {
+      for (key, value) in stat.lines().filter_map(|line| line.split_once(' ')) {
+        if key == "usage_usec" || key == "throttled_usec" {usage.push((format!("cpu.{}", key), value.to_string()))}
+      }
+    }
+    if let Ok(stat) = read_to_string(self.path().join("io.stat")) {
+      let (mut rbytes, mut wbytes) = (0u64, 0u64);
+      for field in stat.split_whitespace() {
+        match field.split_once('=') {
+          Some(("rbytes", n)) => rbytes += n.parse::<u64>().unwrap_or(0),
+          Some(("wbytes", n)) => wbytes += n.parse::<u64>().unwrap_or(0),
+          _ => {}
+        }
+      }
+      usage.push(("io.rbytes".to_string(), rbytes.to_string()));
+      usage.push(("io.wbytes".to_string(), wbytes.to_string()));
+    }
+    usage
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs::read_to_string;
+  use nix::unistd::Pid;
+  use super::*;
+  use crate::tests::temp_root;
+
+  #[test]
+  fn parses_byte_sizes() {
+    assert_eq!(parse_bytes("memory.max", "max"), Ok("max".to_string()));
+    assert_eq!(parse_bytes("memory.max", "512"), Ok("512".to_string()));
+    assert_eq!(parse_bytes("memory.max", "2M"), Ok((2 << 20).to_string()));
+    assert_eq!(parse_bytes("memory.high", "1g"), Ok((1 << 30).to_string()));
+    assert!(parse_bytes("memory.max", "lots").is_err());
+    assert!(parse_bytes("memory.max", "99999999999T").is_err());
+  }
+
+  #[test]
+  fn parses_limits() {
+    let mut limits = CgroupLimits::default();
+    limits.set("cpu.weight", "50").unwrap();
+    limits.set("cpu.max", "20000 100000").unwrap();
+    limits.set("pids.max", "max").unwrap();
+    assert_eq!(limits.cpu_max.as_deref(), Some("20000 100000"));
+    assert!(limits.set("cpu.weight", "0").is_err());
+    assert!(limits.set("cpu.max", "1 2 3").is_err());
+    assert!(limits.set("memory.swap", "1").is_err());
+    assert_eq!(limits.to_string(), "cpu.weight=50, cpu.max=20000 100000, pids.max=max");
+  }
+
+  #[test]
+  fn creates_cgroup_under_fake_root() {
+    let root = temp_root("cgroup-create");
+    let mut limits = CgroupLimits::default();
+    limits.set("cpu.weight", "50").unwrap();
+    limits.set("io.weight", "20").unwrap();
+    limits.set("memory.max", "1M").unwrap();
+    let cgroup = Cgroup::create(&root, "polite/jobs", "alias-enc", &limits).unwrap();
+    assert_eq!(cgroup.name(), "/polite/jobs/alias-enc");
+    for dir in ["", "polite", "polite/jobs"] {
+      assert_eq!(read_to_string(root.join(dir).join("cgroup.subtree_control")).unwrap(), "+cpu +memory +io");
+    }
+    assert_eq!(read_to_string(cgroup.path().join("cpu.weight")).unwrap(), "50");
+    assert_eq!(read_to_string(cgroup.path().join("io.weight")).unwrap(), "default 20");
+    assert_eq!(read_to_string(cgroup.path().join("memory.max")).unwrap(), "1048576");
+    cgroup.add_process(Pid::from_raw(42)).unwrap();
+    assert_eq!(cgroup.procs(), vec![Pid::from_raw(42)]);
+    cgroup.freeze(true).unwrap();
+    assert_eq!(read_to_string(cgroup.path().join("cgroup.freeze")).unwrap(), "1");
+  }
+
+  #[test]
+  fn enables_only_missing_controllers() {
+    let root = temp_root("cgroup-delegated");
+    std::fs::create_dir_all(root.join("user.slice/polite")).unwrap();
+    write(root.join("cgroup.subtree_control"), "cpu io memory pids\n").unwrap();
+    write(root.join("user.slice/cgroup.subtree_control"), "cpu io memory pids\n").unwrap();
+    write(root.join("user.slice/polite/cgroup.subtree_control"), "cpu\n").unwrap();
+    let mut limits = CgroupLimits::default();
+    limits.set("cpu.weight", "50").unwrap();
+    limits.set("memory.max", "1M").unwrap();
+    Cgroup::create(&root, "user.slice/polite", "alias-enc", &limits).unwrap();
+    assert_eq!(read_to_string(root.join("cgroup.subtree_control")).unwrap(), "cpu io memory pids\n");
+    assert_eq!(read_to_string(root.join("user.slice/cgroup.subtree_control")).unwrap(), "cpu io memory pids\n");
+    assert_eq!(read_to_string(root.join("user.slice/polite/cgroup.subtree_control")).unwrap(), "+memory");
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn reads_settings_table() {
+    let mut settings = CgroupSettings::default();
+    settings.update(&"root = \"/tmp/cgroupfs\"\nparent = \"batch.slice/polite\"".parse().unwrap()).unwrap();
+    assert_eq!(settings.root, Some(PathBuf::from("/tmp/cgroupfs")));
+    assert_eq!(settings.parent.as_deref(), Some("batch.slice/polite"));
+    assert!(settings.update(&"root = \"relative\"".parse().unwrap()).is_err());
+    assert!(settings.update(&"parent = \"../escape\"".parse().unwrap()).is_err());
+    assert!(settings.update(&"leaf = \"x\"".parse().unwrap()).is_err());
+  }
+}
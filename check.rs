@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use toml::{Table, Value};
+use toml_edit::{ImDocument, Item, TableLike};
+use crate::cgroup::CgroupSettings;
+use crate::daemon::DaemonSettings;
+use crate::decision::DecisionSettings;
+use crate::online::OnlineSettings;
+use crate::rules::Rule;
+use crate::{config, normalize_alias, is_reserved_alias_line, parse_config_fields};
+
+#[derive(Debug, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+  pub file: String,
+  pub line: usize,
+  pub column: usize,
+  pub severity: Severity,
+  pub message: String
+}
+
+impl std::fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let severity = match self.severity {
+      Severity::Error => "error",
+      Severity::Warning => "warning"
+    };
+    write!(f, "{}:{}:{}: {}: {}", self.file, self.line, self.column, severity, self.message)
+  }
+}
+
+pub fn check_file(file_path: &str) -> Vec<Diagnostic> {
+  let mut diagnostics = Vec::new();
+  let mut report = |line: usize, column: usize, severity: Severity, message: String| {
+    diagnostics.push(Diagnostic {file: file_path.to_string(), line, column, severity, message})
+  };
+  match read_to_string(file_path) {
+    Ok(text) if file_path.ends_with(".toml") => check_toml(&text, &mut report),
+    Ok(text) => check_legacy(&text, &mut report),
+    Err(e) => report(1, 1, Severity::Error, e.to_string())
+  }
+  diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+  diagnostics
+}
+
+fn field_column(line: &str, field: usize) -> usize {
+  let mut offset = 0;
+  for (i, part) in line.split(';').enumerate() {
+    if i == field {return offset + part.len() - part.trim_start().len() + 1}
+    offset += part.len() + 1;
+  }
+  line.len() + 1
+}
+
+fn check_legacy(text: &str, report: &mut impl FnMut(usize, usize, Severity, String)) {
+  let mut seen: HashMap<String, usize> = HashMap::new();
+  let (mut started, mut ended) = (false, false);
+  for (index, raw) in text.lines().enumerate() {
+    let number = index + 1;
+    let line = raw.trim();
+    if line == "-START-" {
+      if started {report(number, 1, Severity::Warning, "repeated -START- marker".to_string())}
+      started = true;
+      continue
+    }
+    if line == "-END-" {
+      if !started {report(number, 1, Severity::Error, "-END- marker without -START-".to_string())}
+      ended = true;
+      break
+    }
+    if !started || line.is_empty() || line.starts_with('#') {continue}
+    if is_reserved_alias_line(line) {
+      report(number, field_column(raw, 0), Severity::Warning, "alias 0 is reserved for automatic selection, line ignored".to_string());
+      continue
+    }
+    match parse_config_fields(line) {
+      Ok((alias, _)) => match seen.get(&alias) {
+        Some(first) => report(number, field_column(raw, 0), Severity::Warning,
+          format!("duplicate alias {}, overrides the definition on line {}", alias, first)),
+        None => {seen.insert(alias, number);}
+      },
+      Err(e) => report(number, field_column(raw, e.field), Severity::Error, e.message)
+    }
+  }
+  if !started {
+    report(1, 1, Severity::Warning, "no -START- marker, the file defines no aliases".to_string());
+  } else if !ended {
+    report(text.lines().count().max(1), 1, Severity::Warning, "missing -END- marker".to_string());
+  }
+}
+
+// Settings tables reject unknown keys as they walk them, so an error naming this key means every key before it parsed.
+const SENTINEL: &str = "\u{10ffff}";
+
+type Update = fn(&Table) -> Result<(), String>;
+
+const SETTINGS: [(&str, Update); 4] = [
+  ("online", |table| OnlineSettings::default().update(table)),
+  ("decision", |table| DecisionSettings::default().update(table)),
+  ("daemon", |table| DaemonSettings::default().update(table)),
+  ("cgroup", |table| CgroupSettings::default().update(table))
+];
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+  let before = &text[..offset.min(text.len())];
+  let line = before.matches('\n').count() + 1;
+  let column = before.chars().count() - before.rfind('\n').map_or(0, |i| before[..=i].chars().count()) + 1;
+  (line, column)
+}
+
+fn key_position(text: &str, spans: Option<&dyn TableLike>, key: &str, fallback: (usize, usize)) -> (usize, usize) {
+  spans.and_then(|spans| spans.get_key_value(key)).and_then(|(key, _)| key.span()).map_or(fallback, |span| line_column(text, span.start))
+}
+
+fn child<'a>(spans: Option<&'a dyn TableLike>, key: &str) -> Option<&'a Item> {
+  spans.and_then(|spans| spans.get(key))
+}
+
+fn element(item: Option<&Item>, index: usize) -> Option<&dyn TableLike> {
+  match item? {
+    Item::ArrayOfTables(tables) => tables.get(index).map(|table| table as &dyn TableLike),
+    item => item.as_array()?.get(index)?.as_inline_table().map(|table| table as &dyn TableLike)
+  }
+}
+
+fn element_position(text: &str, item: Option<&Item>, index: usize, fallback: (usize, usize)) -> (usize, usize) {
+  let span = match item {
+    Some(Item::ArrayOfTables(tables)) => tables.get(index).and_then(|table| table.span()),
+    item => item.and_then(Item::as_array).and_then(|array| array.get(index)).and_then(|value| value.span())
+  };
+  span.map_or(fallback, |span| line_column(text, span.start))
+}
+
+fn check_keys(text: &str, table: &Table, spans: Option<&dyn TableLike>, position: (usize, usize),
+  validate: &dyn Fn(&Table) -> Result<(), String>, report: &mut impl FnMut(usize, usize, Severity, String)) {
+  let only = |key: &String, value: &Value| Table::from_iter([(key.clone(), value.clone())]);
+  let key_error = |key: &String, value: &Value| {
+    let mut table = only(key, value);
+    table.insert(SENTINEL.to_string(), Value::Boolean(true));
+    validate(&table).err().filter(|e| !e.contains(SENTINEL))
+  };
+  let mut clean = true;
+  for (key, value) in table {
+    let (line, column) = key_position(text, spans, key, position);
+    if let Value::Array(elements) = value {
+      let single = |element: &Value| Value::Array(vec![element.clone()]);
+      let Some(index) = elements.iter().position(|element| key_error(key, &single(element)).is_some()) else {continue};
+      clean = false;
+      let e = validate(&only(key, value)).err().or_else(|| key_error(key, &single(&elements[index]))).unwrap_or_default();
+      let (line, column) = element_position(text, child(spans, key), index, (line, column));
+      report(line, column, Severity::Error, e);
+      continue
+    }
+    let Some(e) = key_error(key, value) else {continue};
+    clean = false;
+    match (value.as_table(), child(spans, key).and_then(Item::as_table_like)) {
+      (Some(inner), Some(inner_spans)) => check_keys(text, inner, Some(inner_spans), (line, column),
+        &|inner| validate(&only(key, &Value::Table(inner.clone()))), report),
+      _ => report(line, column, Severity::Error, e)
+    }
+  }
+  if !clean {return}
+  let Err(e) = validate(table) else {return};
+  let (line, column) = match validate(&Table::new()) {
+    Ok(()) => table.iter().find(|(key, value)| validate(&only(key, value)).as_ref().err() == Some(&e))
+      .map_or(position, |(key, _)| key_position(text, spans, key, position)),
+    Err(_) => position
+  };
+  report(line, column, Severity::Error, e)
+}
+
+fn check_toml(text: &str, report: &mut impl FnMut(usize, usize, Severity, String)) {
+  let document: Table = match text.parse() {
+    Ok(document) => document,
+    Err(e) => {
+      let e: toml::de::Error = e;
+      let (line, column) = e.span().map_or((1, 1), |span| line_column(text, span.start));
+      return report(line, column, Severity::Error, e.message().trim().replace('\n', "; "))
+    }
+  };
+  let spans = ImDocument::parse(text).ok();
+  let root = spans.as_ref().map(|spans| spans.as_table() as &dyn TableLike);
+  for (key, value) in &document {
+    let position = key_position(text, root, key, (1, 1));
+    if let Some((_, update)) = SETTINGS.iter().find(|(name, _)| name == key) {
+      match value.as_table() {
+        Some(table) => check_keys(text, table, child(root, key).and_then(Item::as_table_like), position, &|table| update(table), report),
+        None => report(position.0, position.1, Severity::Error, format!("{} must be a table", key))
+      }
+      continue
+    }
+    if key == "rule" {
+      let Some(rules) = value.as_array() else {
+        report(position.0, position.1, Severity::Error, "rule must be an array of tables, use [[rule]]".to_string());
+        continue
+      };
+      for (index, rule) in rules.iter().enumerate() {
+        let (line, column) = element_position(text, child(root, key), index, position);
+        match rule.as_table() {
+          Some(table) => check_keys(text, table, element(child(root, key), index), (line, column),
+            &|table| Rule::parse(table, index, "").map(drop), report),
+          None => report(line, column, Severity::Error, format!("rule {} must be a table", index + 1))
+        }
+      }
+      continue
+    }
+    if key != "alias" {report(position.0, position.1, Severity::Error, format!("unknown top-level key {}", key)); continue}
+    let Some(aliases) = value.as_table() else {
+      report(position.0, position.1, Severity::Error, "alias must be a table of alias tables".to_string());
+      continue
+    };
+    let alias_spans = child(root, key).and_then(Item::as_table_like);
+    for (name, table) in aliases {
+      let (line, column) = key_position(text, alias_spans, name, position);
+      match normalize_alias(name) {
+        Ok(alias) if alias == "0" => {
+          report(line, column, Severity::Warning, "alias 0 is reserved for automatic selection, table ignored".to_string());
+          continue
+        }
+        Ok(_) => {}
+        Err(e) => {report(line, column, Severity::Error, e); continue}
+      }
+      match table.as_table() {
+        Some(table) => check_keys(text, table, child(alias_spans, name).and_then(Item::as_table_like), (line, column),
+          &|table| config::parse_alias_table(table).map(drop).map_err(|e| format!("alias.{}: {}", name, e)), report),
+        None => report(line, column, Severity::Error, format!("alias.{} must be a table", name))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check(name: &str, text: &str) -> Vec<String> {
+    let root = crate::tests::temp_root(&format!("check-{}", name.replace('.', "-")));
+    let path = root.join(name);
+    std::fs::write(&path, text).unwrap();
+    let diagnostics = check_file(&path.display().to_string()).iter()
+      .map(|d| format!("{}:{}: {:?}: {}", d.line, d.column, d.severity, d.message)).collect();
+    std::fs::remove_dir_all(root).unwrap();
+    diagnostics
+  }
+
+  #[test]
+  fn finds_field_columns() {
+    assert_eq!(field_column("a; b;c", 0), 1);
+    assert_eq!(field_column("a; b;c", 1), 4);
+    assert_eq!(field_column("a; b;c", 2), 6);
+    assert_eq!(field_column("  a;b", 0), 3);
+    assert_eq!(field_column("a; b;c", 5), 7);
+  }
+
+  #[test]
+  fn warns_about_legacy_duplicates_and_markers() {
+    let text = "-START-\nenc;10;100\n0;5;0\nenc; 30;0\nweb;5;0;bogus\nenc;5;0\n";
+    assert_eq!(check("polite.conf", text), vec![
+      "3:1: Warning: alias 0 is reserved for automatic selection, line ignored",
+      "4:6: Error: niceness 30 out of range, allowed -20 to 19",
+      "5:9: Error: Unknown I/O class bogus",
+      "6:1: Warning: duplicate alias enc, overrides the definition on line 2",
+      "6:1: Warning: missing -END- marker"
+    ]);
+    assert_eq!(check("empty.conf", "enc;10;100\n"), vec!["1:1: Warning: no -START- marker, the file defines no aliases"]);
+    assert_eq!(check("end.conf", "-END-\n"), vec![
+      "1:1: Error: -END- marker without -START-",
+      "1:1: Warning: no -START- marker, the file defines no aliases"
+    ]);
+    assert!(check("ok.conf", "-START-\nenc;10;100\n-END-\n").is_empty());
+  }
+
+  #[test]
+  fn reports_every_toml_error_at_its_key() {
+    let text = "[alias.enc]\nniceness = 30\nio_class = \"bogus\"\n  oom_score_adj = 5000\n\n[alias.web.cgroup]\n\"cpu.weight\" = 0\n";
+    assert_eq!(check("keys.toml", text), vec![
+      "2:1: Error: alias.enc: niceness 30 out of range, allowed -20 to 19",
+      "3:1: Error: alias.enc: Unknown I/O class bogus",
+      "4:3: Error: alias.enc: oom_score_adj 5000 out of range, allowed -1000 to 1000",
+      "7:1: Error: alias.web: cpu.weight must be between 1 and 10000"
+    ]);
+  }
+
+  #[test]
+  fn reports_dotted_and_inline_positions() {
+    assert_eq!(check("dotted.toml", "alias.dotted.niceness = 99\n"), vec!["1:14: Error: alias.dotted: niceness 99 out of range, allowed -20 to 19"]);
+    let text = "[alias]\ninline = { niceness = 5, io_level = 9 }\n\"0\" = { niceness = 5 }\n\"bad!\" = {}\n";
+    assert_eq!(check("inline.toml", text), vec![
+      "2:26: Error: alias.inline: io_level 9 out of range, allowed 0 to 7",
+      "3:1: Warning: alias 0 is reserved for automatic selection, table ignored",
+      "4:1: Error: Invalid alias name \"bad!\""
+    ]);
+  }
+
+  #[test]
+  fn reports_table_and_element_positions() {
+    let text = "[online]\nttl = \"x\"\nsources = [\"file:///a\", \"ftp://b\"]\n\n[[rule]]\nexe = \"x\"\n\n[alias.ok]\nadaptive = { interval = 3 }\n\n\
+      [[alias.night.schedule]]\nfrom = \"01:00\"\nto = \"02:00\"\n[[alias.night.schedule]]\nfrom = \"25:00\"\nto = \"01:00\"\n\n[decision]\nbackends = [\"http\"]\n";
+    assert_eq!(check("tables.toml", text), vec![
+      "2:1: Error: online.ttl must be a non-negative integer",
+      "3:25: Error: online.sources: unsupported URL ftp://b, expected file://, http:// or https://",
+      "5:1: Error: rule 1: needs an alias other than 0",
+      "9:1: Error: alias.ok: adaptive needs a threshold for at least one of cpu, memory or io",
+      "14:1: Error: alias.night: schedule: window 2: from: invalid time 25:00, expected HH:MM",
+      "19:1: Error: decision.backends lists http, but there is no [decision.http] table"
+    ]);
+    assert_eq!(check("bogus.toml", "bogus = 1\n"), vec!["1:1: Error: unknown top-level key bogus"]);
+  }
+
+  #[test]
+  fn reports_toml_syntax_errors() {
+    assert_eq!(check("syntax.toml", "[alias.enc]\nniceness = = 3\n"), vec!["2:12: Error: invalid string; expected `\"`, `'`"]);
+  }
+}
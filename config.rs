@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use toml::{Table, Value};
+use crate::cgroup::CgroupSettings;
+use crate::daemon::DaemonSettings;
+use crate::decision::DecisionSettings;
+use crate::online::OnlineSettings;
+use crate::rules::Rule;
+use crate::schedule;
+use crate::{PoliteConfig, IoClass, SchedPolicy, NICENESS_RANGE, OOM_SCORE_ADJ_RANGE, IO_LEVEL_RANGE, apply_setting, check_range, validate_config, normalize_alias, parse_config_line,
+  parse_sched_policy, parse_cpu_list, format_cpu_list, format_rlimit_value};
+
+fn value_to_string(key: &str, value: &Value) -> Result<String, String> {
+  match value {
+    Value::String(s) => Ok(s.trim().to_string()),
+    Value::Integer(i) => Ok(i.to_string()),
+    _ => Err(format!("{} must be a string or integer", key))
+  }
+}
+
+fn value_to_i32(key: &str, value: &Value) -> Result<i32, String> {
+  value.as_integer().and_then(|i| i32::try_from(i).ok()).ok_or_else(|| format!("{} must be an integer", key))
+}
+
+pub fn parse_alias_table(table: &Table) -> Result<PoliteConfig, String> {
+  let mut config = PoliteConfig {io_level: 4, ..PoliteConfig::default()};
+  for (key, value) in table {
+    match key.as_str() {
+      "niceness" => config.niceness = Some(check_range(key, value_to_i32(key, value)?, NICENESS_RANGE)?),
+      "oom_score_adj" => config.oom_score_adj = Some(check_range(key, value_to_i32(key, value)?, OOM_SCORE_ADJ_RANGE)?),
+      "io_class" => config.io_class = value_to_string(key, value)?.parse()?,
+      "io_level" => config.io_level = check_range(key, value_to_i32(key, value)?, IO_LEVEL_RANGE)?,
+      "sched_policy" => {
+        let (policy, reset_on_fork) = parse_sched_policy(&value_to_string(key, value)?)?;
+        config.sched_policy = policy;
+        config.sched_reset_on_fork |= reset_on_fork;
+      }
+      "sched_reset_on_fork" => config.sched_reset_on_fork = value.as_bool().ok_or("sched_reset_on_fork must be a boolean")?,
+      "cpus" => config.cpus = Some(parse_cpu_list(&value_to_string(key, value)?)?),
+      "numa" => config.numa = Some(value_to_string(key, value)?.parse()?),
+      "on_battery" | "min_charge" => apply_setting(&mut config, key, &value_to_string(key, value)?)?,
+      "rlimits" | "cgroup" => {
+        let settings = value.as_table().ok_or_else(|| format!("{} must be a table", key))?;
+        for (setting, value) in settings {apply_setting(&mut config, setting, &value_to_string(setting, value)?)?}
+      }
+      "schedule" => config.schedule = schedule::parse_windows(value, table)?,
+      "adaptive" | "idle" => {
+        let settings = value.as_table().ok_or_else(|| format!("{} must be a table", key))?;
+        for (setting, value) in settings {
+          let value = match value {
+            Value::Boolean(b) => b.to_string(),
+            Value::Float(f) => f.to_string(),
+            value => value_to_string(setting, value)?
+          };
+          apply_setting(&mut config, &format!("{}.{}", key, setting), &value)?;
+        }
+      }
+      _ => return Err(format!("Unknown key {}", key))
+    }
+  }
+  validate_config(&config)?;
+  Ok(config)
+}
+
+#[derive(Default)]
+pub struct LoadedConfig {
+  pub aliases: HashMap<String, (PoliteConfig, String)>,
+  pub rules: Vec<Rule>,
+  pub online: OnlineSettings,
+  pub decision: DecisionSettings,
+  pub daemon: DaemonSettings,
+  pub cgroup: CgroupSettings
+}
+
+pub fn parse_rule_tables(value: &Value, source: &str) -> Result<Vec<Rule>, String> {
+  let tables = value.as_array().ok_or("rule must be an array of tables, use [[rule]]")?;
+  tables.iter().enumerate().map(|(index, table)| {
+    let table = table.as_table().ok_or_else(|| format!("rule {} must be a table", index + 1))?;
+    Rule::parse(table, index, source)
+  }).collect()
+}
+
+pub fn parse_toml_config(text: &str, source: &str, loaded: &mut LoadedConfig) -> Result<(), String> {
+  let document: Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+  for (key, value) in &document {
+    match key.as_str() {
+      "online" => loaded.online.update(value.as_table().ok_or("online must be a table")?)?,
+      "decision" => loaded.decision.update(value.as_table().ok_or("decision must be a table")?)?,
+      "daemon" => loaded.daemon.update(value.as_table().ok_or("daemon must be a table")?)?,
+      "cgroup" => loaded.cgroup.update(value.as_table().ok_or("cgroup must be a table")?)?,
+      "rule" => {
+        let rules = parse_rule_tables(value, source)?;
+        loaded.rules.splice(0..0, rules);
+      }
+      "alias" => {
+        let aliases = value.as_table().ok_or("alias must be a table of alias tables")?;
+        for (name, table) in aliases {
+          let alias = normalize_alias(name)?;
+          if alias == "0" {continue}
+          let table = table.as_table().ok_or_else(|| format!("alias.{} must be a table", name))?;
+          let config = parse_alias_table(table).map_err(|e| format!("alias.{}: {}", name, e))?;
+          loaded.aliases.insert(alias, (config, source.to_string()));
+        }
+      }
+      _ => return Err(format!("Unknown top-level key {}", key))
+    }
+  }
+  Ok(())
+}
+
+pub fn load_toml_config(file_path: &str, loaded: &mut LoadedConfig) -> Result<(), String> {
+  let text = read_to_string(file_path).map_err(|e| e.to_string())?;
+  parse_toml_config(&text, file_path, loaded)
+}
+
+fn toml_key(key: &str) -> String {
+  if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {key.to_string()} else {Value::from(key).to_string()}
+}
+
+fn toml_value(value: String) -> Value {
+  value.parse::<i64>().map(Value::from).or_else(|_| value.parse::<f64>().map(Value::from))
+    .or_else(|_| value.parse::<bool>().map(Value::from)).unwrap_or_else(|_| Value::from(value))
+}
+
+pub fn to_toml(alias: &str, config: &PoliteConfig) -> String {
+  let mut out = format!("[alias.{}]\n", toml_key(alias));
+  if let Some(niceness) = config.niceness {out.push_str(&format!("niceness = {}\n", niceness))}
+  if let Some(oom_score_adj) = config.oom_score_adj {out.push_str(&format!("oom_score_adj = {}\n", oom_score_adj))}
+  if config.io_class != IoClass::None {
+    out.push_str(&format!("io_class = \"{}\"\nio_level = {}\n", config.io_class, config.io_level));
+  }
+  if config.sched_policy != SchedPolicy::Unchanged {
+    out.push_str(&format!("sched_policy = \"{}\"\n", config.sched_policy));
+    if config.sched_reset_on_fork {out.push_str("sched_reset_on_fork = true\n")}
+  }
+  if let Some(cpus) = &config.cpus {out.push_str(&format!("cpus = \"{}\"\n", format_cpu_list(cpus)))}
+  if let Some(numa) = &config.numa {out.push_str(&format!("numa = \"{}\"\n", numa))}
+  if let Some(power) = &config.power {
+    for (key, value) in power.settings() {out.push_str(&format!("{} = {}\n", key, toml_value(value)))}
+  }
+  if !config.rlimits.is_empty() {
+    out.push_str(&format!("\n[alias.{}.rlimits]\n", toml_key(alias)));
+    for (name, soft, hard) in &config.rlimits {
+      out.push_str(&format!("{} = \"{}:{}\"\n", name, format_rlimit_value(*soft), format_rlimit_value(*hard)));
+    }
+  }
+  if !config.cgroup.is_empty() {
+    out.push_str(&format!("\n[alias.{}.cgroup]\n", toml_key(alias)));
+    for (key, value) in config.cgroup.settings() {
+      out.push_str(&format!("{} = {}\n", toml_key(key), toml_value(value)));
+    }
+  }
+  if let Some(adaptive) = &config.adaptive {
+    out.push_str(&format!("\n[alias.{}.adaptive]\n", toml_key(alias)));
+    for (key, value) in adaptive.settings() {out.push_str(&format!("{} = {}\n", key, toml_value(value)))}
+  }
+  if let Some(idle) = &config.idle {
+    out.push_str(&format!("\n[alias.{}.idle]\n", toml_key(alias)));
+    for (key, value) in idle.settings() {out.push_str(&format!("{} = {}\n", key, toml_value(value)))}
+  }
+  out
+}
+
+pub fn convert_legacy_config(file_path: &str) -> Result<String, String> {
+  let text = read_to_string(file_path).map_err(|e| e.to_string())?;
+  let mut out = String::new();
+  let mut comments = Vec::new();
+  let mut in_section = false;
+  for (number, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line == "-START-" {in_section = true; continue}
+    if line == "-END-" {break}
+    if !in_section {continue}
+    if line.starts_with('#') {comments.push(line); continue}
+    if line.is_empty() {continue}
+    let (alias, config) = parse_config_line(line).map_err(|e| format!("{}:{}: {}", file_path, number + 1, e))?;
+    if !out.is_empty() {out.push('\n')}
+    for comment in comments.drain(..) {out.push_str(comment); out.push('\n')}
+    out.push_str(&to_toml(&alias, &config));
+  }
+  Ok(out)
+}
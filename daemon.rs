@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use glob::Pattern;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{getpid, Pid};
+use toml::Table;
+use crate::config::LoadedConfig;
+use crate::online::{self, Ruleset};
+use crate::rules::{self, Process};
+use crate::{cgroup, procfs, apply_to_pid, PoliteConfig};
+
+const NETLINK_CONNECTOR: libc::c_int = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_EXEC: u32 = 2;
+const NLMSG_HEADER_LEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventSource {
+  Auto,
+  Netlink,
+  Poll
+}
+
+#[derive(Debug, Clone)]
+pub struct DaemonSettings {
+  pub source: EventSource,
+  pub poll_interval: u64,
+  pub rate_limit: u32,
+  pub exclude: Vec<String>
+}
+
+impl Default for DaemonSettings {
+  fn default() -> Self {
+    DaemonSettings {source: EventSource::Auto, poll_interval: 1, rate_limit: 50, exclude: Vec::new()}
+  }
+}
+
+impl DaemonSettings {
+  pub fn update(&mut self, table: &Table) -> Result<(), String> {
+    for (key, value) in table {
+      match key.as_str() {
+        "source" => self.source = match value.as_str() {
+          Some("auto") => EventSource::Auto,
+          Some("netlink") => EventSource::Netlink,
+          Some("poll") => EventSource::Poll,
+          _ => return Err("daemon.source must be auto, netlink or poll".to_string())
+        },
+        "poll_interval" => self.poll_interval = value.as_integer().and_then(|i| u64::try_from(i).ok()).filter(|i| *i > 0)
+          .ok_or("daemon.poll_interval must be a positive integer")?,
+        "rate_limit" => self.rate_limit = value.as_integer().and_then(|i| u32::try_from(i).ok()).filter(|i| *i > 0)
+          .ok_or("daemon.rate_limit must be a positive integer")?,
+        "exclude" => {
+          let patterns = value.as_array().ok_or("daemon.exclude must be an array of patterns")?;
+          self.exclude = patterns.iter().map(|pattern| {
+            let pattern = pattern.as_str().ok_or("daemon.exclude must be an array of patterns")?;
+            Pattern::new(pattern).map_err(|e| format!("daemon.exclude: invalid glob {}: {}", pattern, e))?;
+            Ok(pattern.to_string())
+          }).collect::<Result<_, String>>()?;
+        }
+        _ => return Err(format!("Unknown key daemon.{}", key))
+      }
+    }
+    Ok(())
+  }
+}
+
+struct NetlinkSocket(libc::c_int);
+
+impl NetlinkSocket {
+  fn open() -> Result<NetlinkSocket, String> {
+    let fd = unsafe {libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, NETLINK_CONNECTOR)};
+    if fd == -1 {return Err(format!("Netlink error: {}", Errno::last()))}
+    let socket = NetlinkSocket(fd);
+    let mut address: libc::sockaddr_nl = unsafe {std::mem::zeroed()};
+    address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    address.nl_groups = CN_IDX_PROC;
+    address.nl_pid = getpid().as_raw() as u32;
+    let length = std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+    if unsafe {libc::bind(fd, &address as *const libc::sockaddr_nl as *const libc::sockaddr, length)} == -1 {
+      return Err(format!("Netlink error: bind: {}", Errno::last()))
+    }
+    let timeout = libc::timeval {tv_sec: RECEIVE_TIMEOUT.as_secs() as libc::time_t, tv_usec: 0};
+    let timeout_length = std::mem::size_of::<libc::timeval>() as libc::socklen_t;
+    if unsafe {libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout as *const libc::timeval as *const libc::c_void, timeout_length)} == -1 {
+      return Err(format!("Netlink error: receive timeout: {}", Errno::last()))
+    }
+    let mut message = Vec::with_capacity(NLMSG_HEADER_LEN + CN_MSG_LEN + 4);
+    message.extend(((NLMSG_HEADER_LEN + CN_MSG_LEN + 4) as u32).to_ne_bytes());
+    message.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+    message.extend(0u16.to_ne_bytes());
+    message.extend(0u32.to_ne_bytes());
+    message.extend((getpid().as_raw() as u32).to_ne_bytes());
+    for field in [CN_IDX_PROC, CN_VAL_PROC, 0, 0] {message.extend(field.to_ne_bytes())}
+    message.extend(4u16.to_ne_bytes());
+    message.extend(0u16.to_ne_bytes());
+    message.extend(PROC_CN_MCAST_LISTEN.to_ne_bytes());
+    if unsafe {libc::send(fd, message.as_ptr() as *const libc::c_void, message.len(), 0)} == -1 {
+      return Err(format!("Netlink error: subscribe: {}", Errno::last()))
+    }
+    Ok(socket)
+  }
+
+  fn exec_events(&self) -> Result<Vec<Pid>, String> {
+    let mut buffer = [0u8; 8192];
+    let received = unsafe {libc::recv(self.0, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0)};
+    if received == -1 {
+      return match Errno::last() {
+        Errno::EINTR | Errno::ENOBUFS | Errno::EAGAIN => Ok(Vec::new()),
+        e => Err(format!("Netlink error: {}", e))
+      }
+    }
+    Ok(exec_pids(&buffer[..received as usize]))
+  }
+}
+
+fn exec_pids(buffer: &[u8]) -> Vec<Pid> {
+  let u32_at = |offset: usize| buffer.get(offset..offset + 4).map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap_or_default()));
+  let mut pids = Vec::new();
+  let mut offset = 0;
+  while let Some(length) = u32_at(offset).map(|length| length as usize).filter(|length| *length >= NLMSG_HEADER_LEN) {
+    let event = offset + NLMSG_HEADER_LEN + CN_MSG_LEN;
+    if u32_at(event) == Some(PROC_EVENT_EXEC) {
+      if let Some(tgid) = u32_at(event + 20) {pids.push(Pid::from_raw(tgid as i32))}
+    }
+    offset += (length + 3) & !3;
+  }
+  pids
+}
+
+impl Drop for NetlinkSocket {
+  fn drop(&mut self) {
+    unsafe {libc::close(self.0)};
+  }
+}
+
+struct RateLimit {
+  limit: u32,
+  window: Instant,
+  used: u32,
+  dropped: u32
+}
+
+impl RateLimit {
+  fn allow(&mut self) -> bool {
+    if self.window.elapsed() >= Duration::from_secs(1) {
+      if self.dropped > 0 {eprintln!("Rate limit of {} per second reached, skipped {} process(es)", self.limit, self.dropped)}
+      *self = RateLimit {limit: self.limit, window: Instant::now(), used: 0, dropped: 0};
+    }
+    if self.used < self.limit {self.used += 1; true} else {self.dropped += 1; false}
+  }
+}
+
+struct Daemon {
+  configs: LoadedConfig,
+  online: Option<Ruleset>,
+  refreshed: Instant,
+  exclude: Vec<Pattern>,
+  cgroups: HashMap<String, cgroup::Cgroup>,
+  rate_limit: RateLimit
+}
+
+impl Daemon {
+  fn excluded(&self, process: &Process) -> bool {
+    let executable = process.executable();
+    self.exclude.iter().any(|pattern| pattern.matches(&executable) || pattern.matches_path(&process.path))
+  }
+
+  fn resolve(&self, alias: &str) -> Option<PoliteConfig> {
+    self.configs.aliases.get(alias).map(|(config, _)| config)
+      .or_else(|| self.online.as_ref().and_then(|ruleset| ruleset.configs.get(alias))).cloned()
+  }
+
+  fn refresh(&mut self, reload: &dyn Fn() -> Result<LoadedConfig, String>) {
+    let interval = Duration::from_secs(self.configs.online.ttl).max(MIN_REFRESH);
+    let expired = !self.configs.online.sources.is_empty() && self.refreshed.elapsed() >= interval;
+    let requested = RELOAD.swap(false, Ordering::SeqCst);
+    if !requested && !expired {return}
+    if requested {
+      match reload().and_then(|configs| Ok((exclude_patterns(&configs.daemon)?, configs))) {
+        Ok((exclude, configs)) => {
+          println!("Reloaded configuration with {} local rule(s)", configs.rules.len());
+          self.exclude = exclude;
+          self.rate_limit.limit = configs.daemon.rate_limit;
+          self.configs = configs;
+        }
+        Err(e) => eprintln!("{}, keeping the previous configuration", e)
+      }
+    }
+    self.refreshed = Instant::now();
+    self.online = load_ruleset(&self.configs);
+    self.cgroups.clear();
+    match &self.online {
+      Some(ruleset) => println!("Reloaded online ruleset from {} with {} rule(s)", ruleset.source, ruleset.rules.len()),
+      None => println!("Reloaded online ruleset: none available")
+    }
+  }
+
+  fn handle(&mut self, pid: Pid) -> bool {
+    if pid == getpid() {return true}
+    let Ok(process) = Process::for_pid(pid) else {return true};
+    if self.excluded(&process) {return true}
+    if !self.rate_limit.allow() {return false}
+    let online_rules = self.online.as_ref().map_or(&[][..], |ruleset| &ruleset.rules[..]);
+    let Some(rule) = rules::select(self.configs.rules.iter().chain(online_rules), &process) else {return true};
+    let Some(mut config) = self.resolve(&rule.alias) else {
+      eprintln!("PID {} ({}): {} selects alias {}, which is not defined", pid, process.executable(), rule.name, rule.alias);
+      return true
+    };
+    config.numa = None;
+    if !config.cgroup.is_empty() && !self.cgroups.contains_key(&rule.alias) {
+      match cgroup::Cgroup::create(&self.configs.cgroup.root(), &self.configs.cgroup.parent(), &format!("alias-{}", rule.alias), &config.cgroup) {
+        Ok(job_cgroup) => {self.cgroups.insert(rule.alias.clone(), job_cgroup);}
+        Err(e) => {eprintln!("PID {} ({}): {}", pid, process.executable(), e); return true}
+      }
+    }
+    match apply_to_pid(pid, &config, self.cgroups.get(&rule.alias)) {
+      Ok(()) => println!("PID {} ({}): {} applied alias {}", pid, process.executable(), rule.name, rule.alias),
+      Err(e) => eprintln!("PID {} ({}): {} failed: {}", pid, process.executable(), rule.name, e)
+    }
+    true
+  }
+}
+
+fn executable(pid: Pid) -> Option<PathBuf> {
+  std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+fn load_ruleset(configs: &LoadedConfig) -> Option<Ruleset> {
+  match online::load(&configs.online) {
+    Ok(ruleset) => {
+      if let Some(e) = &ruleset.stale {eprintln!("{}, using cached ruleset fetched at {}", e, ruleset.fetched)}
+      Some(ruleset)
+    }
+    Err(e) => {
+      if !configs.online.sources.is_empty() {eprintln!("{}", e)}
+      None
+    }
+  }
+}
+
+fn exclude_patterns(settings: &DaemonSettings) -> Result<Vec<Pattern>, String> {
+  settings.exclude.iter().map(|pattern| Pattern::new(pattern).map_err(|e| e.to_string())).collect()
+}
+
+extern "C" fn request_reload(_: libc::c_int) {
+  RELOAD.store(true, Ordering::SeqCst);
+}
+
+pub fn run(configs: LoadedConfig, reload: impl Fn() -> Result<LoadedConfig, String>) -> Result<(), String> {
+  let action = SigAction::new(SigHandler::Handler(request_reload), SaFlags::empty(), SigSet::empty());
+  unsafe {sigaction(Signal::SIGHUP, &action)}.map_err(|e| format!("Signal error: {}", e))?;
+  let online = load_ruleset(&configs);
+  let exclude = exclude_patterns(&configs.daemon)?;
+  let rate_limit = RateLimit {limit: configs.daemon.rate_limit, window: Instant::now(), used: 0, dropped: 0};
+  let rules = configs.rules.len() + online.as_ref().map_or(0, |ruleset| ruleset.rules.len());
+  let socket = match configs.daemon.source {
+    EventSource::Poll => None,
+    EventSource::Netlink => Some(NetlinkSocket::open()?),
+    EventSource::Auto => NetlinkSocket::open().map_err(|e| eprintln!("{}, falling back to polling /proc", e)).ok()
+  };
+  println!("Enforcing {} rule(s) using {}", rules, if socket.is_some() {"the proc connector"} else {"/proc polling"});
+  let mut daemon = Daemon {configs, online, refreshed: Instant::now(), exclude, cgroups: HashMap::new(), rate_limit};
+  let mut seen: HashMap<Pid, Option<PathBuf>> = HashMap::new();
+  for pid in procfs::pids() {
+    if daemon.handle(pid) {seen.insert(pid, executable(pid));}
+  }
+  loop {
+    daemon.refresh(&reload);
+    match &socket {
+      Some(socket) => for pid in socket.exec_events()? {daemon.handle(pid);},
+      None => {
+        std::thread::sleep(Duration::from_secs(daemon.configs.daemon.poll_interval));
+        let pids = procfs::pids();
+        let live: HashSet<Pid> = pids.iter().copied().collect();
+        seen.retain(|pid, _| live.contains(pid));
+        for pid in pids {
+          let path = executable(pid);
+          if seen.get(&pid) == Some(&path) {continue}
+          if daemon.handle(pid) {seen.insert(pid, path);}
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(what: u32, pid: u32, tgid: u32) -> Vec<u8> {
+    let mut event = Vec::new();
+    for field in [what, 0] {event.extend(field.to_ne_bytes())}
+    event.extend(123456789u64.to_ne_bytes());
+    for field in [pid, tgid] {event.extend(field.to_ne_bytes())}
+    let mut message = Vec::new();
+    message.extend(((NLMSG_HEADER_LEN + CN_MSG_LEN + event.len()) as u32).to_ne_bytes());
+    message.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+    message.extend(0u16.to_ne_bytes());
+    for field in [7, 0, CN_IDX_PROC, CN_VAL_PROC, 7, 0] {message.extend(field.to_ne_bytes())}
+    message.extend((event.len() as u16).to_ne_bytes());
+    message.extend(0u16.to_ne_bytes());
+    message.extend(event);
+    message
+  }
+
+  #[test]
+  fn reads_exec_tgid() {
+    assert_eq!(exec_pids(&message(PROC_EVENT_EXEC, 4321, 4300)), vec![Pid::from_raw(4300)]);
+  }
+
+  #[test]
+  fn skips_other_events() {
+    assert_eq!(exec_pids(&message(1, 4321, 4300)), Vec::new());
+    assert_eq!(exec_pids(&message(0x80000000, 4321, 4300)), Vec::new());
+  }
+
+  #[test]
+  fn walks_aligned_messages() {
+    let mut buffer = message(PROC_EVENT_EXEC, 10, 10);
+    buffer.extend([0; 2]);
+    let length = buffer.len() as u32;
+    buffer[..4].copy_from_slice(&length.to_ne_bytes());
+    buffer.extend([0; 2]);
+    buffer.extend(message(1, 11, 11));
+    buffer.extend(message(PROC_EVENT_EXEC, 13, 12));
+    assert_eq!(exec_pids(&buffer), vec![Pid::from_raw(10), Pid::from_raw(12)]);
+  }
+
+  #[test]
+  fn reloads_configuration_on_request() {
+    let rate_limit = RateLimit {limit: 50, window: Instant::now(), used: 0, dropped: 0};
+    let mut daemon = Daemon {configs: LoadedConfig::default(), online: None, refreshed: Instant::now(), exclude: Vec::new(), cgroups: HashMap::new(), rate_limit};
+    daemon.refresh(&|| panic!("reloaded without SIGHUP"));
+    RELOAD.store(true, Ordering::SeqCst);
+    daemon.refresh(&|| Err("Config error: /etc/polite.toml: bad".to_string()));
+    assert_eq!((daemon.rate_limit.limit, daemon.exclude.len()), (50, 0));
+    RELOAD.store(true, Ordering::SeqCst);
+    daemon.refresh(&|| {
+      let daemon = DaemonSettings {rate_limit: 7, exclude: vec!["/usr/bin/*".to_string()], ..DaemonSettings::default()};
+      Ok(LoadedConfig {daemon, ..LoadedConfig::default()})
+    });
+    assert_eq!((daemon.rate_limit.limit, daemon.exclude.len(), daemon.configs.daemon.rate_limit), (7, 1, 7));
+  }
+
+  #[test]
+  fn ignores_truncated_messages() {
+    let buffer = message(PROC_EVENT_EXEC, 4321, 4300);
+    assert_eq!(exec_pids(&buffer[..buffer.len() - 4]), Vec::new());
+    assert_eq!(exec_pids(&buffer[..8]), Vec::new());
+    assert_eq!(exec_pids(&[]), Vec::new());
+  }
+}
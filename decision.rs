@@ -0,0 +1,307 @@
+use std::time::Duration;
+use reqwest::blocking::Client;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use nix::unistd::getpid;
+use serde_json::{json, Value};
+use toml::Table;
+use crate::config::{self, LoadedConfig};
+use crate::heuristics;
+use crate::online::Ruleset;
+use crate::rules::{self, Process};
+use crate::{get_applied_settings, IoClass, PoliteConfig};
+
+const BACKENDS: [&str; 3] = ["rules", "heuristics", "http"];
+const MODEL_KEYS: [&str; 5] = ["niceness", "oom_score_adj", "io_class", "io_level", "sched_policy"];
+
+const SYSTEM_PROMPT: &str = "You choose scheduling settings for a program that is about to be launched on a shared Linux \
+machine. Reply with a single JSON object {\"config\": {...}, \"confidence\": <0.0-1.0>, \"explanation\": \"...\"}. The config \
+object may only contain niceness (up to 19), oom_score_adj (0..1000), io_class (none, best-effort, idle), io_level (4..7) and \
+sched_policy (other, batch, idle); settings can only lower a program's priority. Argument values are redacted. Prefer lower \
+priority for batch and background work and leave interactive programs alone.";
+
+#[derive(Debug, Clone)]
+pub struct Decision {
+  pub config: PoliteConfig,
+  pub confidence: f64,
+  pub explanation: String
+}
+
+pub trait DecisionBackend {
+  fn name(&self) -> &'static str;
+  fn decide(&self, process: &Process) -> Result<Option<Decision>, String>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpSettings {
+  pub url: String,
+  pub model: String,
+  pub timeout: u64,
+  pub api_key_env: Option<String>,
+  pub include_args: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct DecisionSettings {
+  pub backends: Vec<String>,
+  pub min_confidence: f64,
+  pub http: Option<HttpSettings>
+}
+
+impl Default for DecisionSettings {
+  fn default() -> Self {
+    DecisionSettings {backends: vec!["rules".to_string(), "heuristics".to_string()], min_confidence: 0.5, http: None}
+  }
+}
+
+impl DecisionSettings {
+  pub fn update(&mut self, table: &Table) -> Result<(), String> {
+    for (key, value) in table {
+      match key.as_str() {
+        "backends" => {
+          let backends = value.as_array().ok_or("decision.backends must be an array of backend names")?;
+          self.backends = backends.iter().map(|backend| match backend.as_str() {
+            Some(name) if BACKENDS.contains(&name) => Ok(name.to_string()),
+            Some(name) => Err(format!("decision.backends: unknown backend {}, expected one of {}", name, BACKENDS.join(", "))),
+            None => Err("decision.backends must be an array of backend names".to_string())
+          }).collect::<Result<_, String>>()?;
+        }
+        "min_confidence" => {
+          let confidence = value.as_float().or_else(|| value.as_integer().map(|i| i as f64))
+            .ok_or("decision.min_confidence must be a number")?;
+          if !(0.0..=1.0).contains(&confidence) {return Err("decision.min_confidence must be between 0 and 1".to_string())}
+          self.min_confidence = confidence;
+        }
+        "http" => self.http = Some(parse_http(value.as_table().ok_or("decision.http must be a table")?)?),
+        _ => return Err(format!("Unknown key decision.{}", key))
+      }
+    }
+    if self.backends.iter().any(|backend| backend == "http") && self.http.is_none() {
+      return Err("decision.backends lists http, but there is no [decision.http] table".to_string())
+    }
+    Ok(())
+  }
+}
+
+fn parse_http(table: &Table) -> Result<HttpSettings, String> {
+  let mut http = HttpSettings {timeout: 30, ..HttpSettings::default()};
+  for (key, value) in table {
+    match key.as_str() {
+      "url" => {
+        let url = value.as_str().ok_or("decision.http.url must be a string")?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+          return Err(format!("decision.http.url: unsupported URL {}, expected http:// or https://", url))
+        }
+        http.url = url.to_string();
+      }
+      "model" => http.model = value.as_str().ok_or("decision.http.model must be a string")?.to_string(),
+      "timeout" => http.timeout = value.as_integer().and_then(|i| u64::try_from(i).ok()).ok_or("decision.http.timeout must be a non-negative integer")?,
+      "api_key_env" => http.api_key_env = Some(value.as_str().ok_or("decision.http.api_key_env must be a string")?.to_string()),
+      "include_args" => http.include_args = value.as_bool().ok_or("decision.http.include_args must be a boolean")?,
+      _ => return Err(format!("Unknown key decision.http.{}", key))
+    }
+  }
+  if http.url.is_empty() || http.model.is_empty() {return Err("decision.http needs a url and a model".to_string())}
+  Ok(http)
+}
+
+pub struct RulesBackend<'a> {
+  local: &'a LoadedConfig,
+  online: Option<&'a Ruleset>
+}
+
+impl DecisionBackend for RulesBackend<'_> {
+  fn name(&self) -> &'static str {"rules"}
+
+  fn decide(&self, process: &Process) -> Result<Option<Decision>, String> {
+    let online_rules = self.online.map_or(&[][..], |ruleset| &ruleset.rules[..]);
+    let Some(rule) = rules::select(self.local.rules.iter().chain(online_rules), process) else {return Ok(None)};
+    let config = self.local.aliases.get(&rule.alias).map(|(config, _)| config)
+      .or_else(|| self.online.and_then(|ruleset| ruleset.configs.get(&rule.alias)))
+      .ok_or_else(|| format!("{} ({}) selects alias {}, which is not defined", rule.name, rule.source, rule.alias))?;
+    let explanation = format!("{} ({}) selects alias {}", rule.name, rule.source, rule.alias);
+    Ok(Some(Decision {config: config.clone(), confidence: 1.0, explanation}))
+  }
+}
+
+pub struct HeuristicBackend;
+
+impl DecisionBackend for HeuristicBackend {
+  fn name(&self) -> &'static str {"heuristics"}
+
+  fn decide(&self, process: &Process) -> Result<Option<Decision>, String> {
+    let classification = heuristics::classify(process);
+    let explanation = format!("classified as {}: {}", classification.class, classification.reasons.join("; "));
+    Ok(Some(Decision {config: classification.class.default_config(), confidence: classification.confidence, explanation}))
+  }
+}
+
+pub struct HttpBackend {
+  settings: HttpSettings
+}
+
+fn redact(arg: &str) -> String {
+  match arg.strip_prefix("--") {
+    Some(option) => match option.split_once('=') {
+      Some((name, _)) => format!("--{}=<value>", name),
+      None => arg.to_string()
+    },
+    None if arg.starts_with('-') => arg.chars().take(2).collect(),
+    None => "<arg>".to_string()
+  }
+}
+
+fn restrict(mut config: PoliteConfig, niceness: i32) -> PoliteConfig {
+  config.niceness = config.niceness.map(|n| n.max(niceness));
+  config.oom_score_adj = config.oom_score_adj.map(|adj| adj.max(0));
+  if config.io_class == IoClass::Realtime {config.io_class = IoClass::BestEffort}
+  config.io_level = config.io_level.max(4);
+  config
+}
+
+impl HttpBackend {
+  fn request(&self, process: &Process) -> Value {
+    let args: Vec<String> = process.argv.iter().skip(1).map(|arg| if self.settings.include_args {arg.clone()} else {redact(arg)}).collect();
+    let prompt = format!("Program: {}\nArguments: {}\nUser: {}", process.path.display(), args.join(" "), process.user);
+    json!({
+      "model": self.settings.model,
+      "temperature": 0,
+      "response_format": {"type": "json_object"},
+      "messages": [{"role": "system", "content": SYSTEM_PROMPT}, {"role": "user", "content": prompt}]
+    })
+  }
+}
+
+impl DecisionBackend for HttpBackend {
+  fn name(&self) -> &'static str {"http"}
+
+  fn decide(&self, process: &Process) -> Result<Option<Decision>, String> {
+    let client = Client::builder().timeout(Duration::from_secs(self.settings.timeout)).build().map_err(|e| format!("Backend error: {}", e))?;
+    let mut request = client.post(&self.settings.url).header(CONTENT_TYPE, "application/json").body(self.request(process).to_string());
+    if let Some(key) = self.settings.api_key_env.as_ref().and_then(|name| std::env::var(name).ok()) {
+      request = request.header(AUTHORIZATION, format!("Bearer {}", key));
+    }
+    let response = request.send().and_then(|r| r.error_for_status()).and_then(|r| r.text()).map_err(|e| format!("Backend error: {}", e))?;
+    let response: Value = serde_json::from_str(&response).map_err(|e| format!("Backend error: malformed response: {}", e))?;
+    let content = response.pointer("/choices/0/message/content").and_then(Value::as_str)
+      .ok_or("Backend error: response has no choices[0].message.content")?;
+    let answer: Value = serde_json::from_str(content.trim()).map_err(|e| format!("Backend error: model did not answer in JSON: {}", e))?;
+    let table: Table = serde_json::from_value(answer.get("config").cloned().unwrap_or(Value::Null))
+      .map_err(|e| format!("Backend error: config must be an object: {}", e))?;
+    if let Some(key) = table.keys().find(|key| !MODEL_KEYS.contains(&key.as_str())) {
+      return Err(format!("Backend error: model set {}, only {} are allowed", key, MODEL_KEYS.join(", ")))
+    }
+    let config = config::parse_alias_table(&table).map_err(|e| format!("Backend error: {}", e))?;
+    let niceness = get_applied_settings(getpid()).ok().and_then(|current| current.niceness).unwrap_or(0);
+    let config = restrict(config, niceness);
+    let confidence = answer.get("confidence").and_then(Value::as_f64).unwrap_or(0.0).clamp(0.0, 1.0);
+    let explanation = answer.get("explanation").and_then(Value::as_str).unwrap_or("no explanation given").to_string();
+    Ok(Some(Decision {config, confidence, explanation: format!("{}: {}", self.settings.model, explanation)}))
+  }
+}
+
+pub fn backends<'a>(local: &'a LoadedConfig, online: Option<&'a Ruleset>) -> Vec<Box<dyn DecisionBackend + 'a>> {
+  local.decision.backends.iter().filter_map(|name| -> Option<Box<dyn DecisionBackend + 'a>> {
+    match name.as_str() {
+      "rules" => Some(Box::new(RulesBackend {local, online})),
+      "heuristics" => Some(Box::new(HeuristicBackend)),
+      "http" => local.decision.http.clone().map(|settings| Box::new(HttpBackend {settings}) as Box<dyn DecisionBackend>),
+      _ => None
+    }
+  }).collect()
+}
+
+pub struct Outcome {
+  pub backend: &'static str,
+  pub result: Result<Option<Decision>, String>
+}
+
+pub struct Trace {
+  pub outcomes: Vec<Outcome>,
+  chosen: Option<usize>
+}
+
+impl Trace {
+  pub fn chosen(&self) -> Option<(&'static str, &Decision)> {
+    let outcome = &self.outcomes[self.chosen?];
+    outcome.result.as_ref().ok().and_then(Option::as_ref).map(|decision| (outcome.backend, decision))
+  }
+}
+
+pub fn decide(backends: &[Box<dyn DecisionBackend + '_>], min_confidence: f64, process: &Process) -> Trace {
+  let mut trace = Trace {outcomes: Vec::new(), chosen: None};
+  for backend in backends {
+    let result = backend.decide(process);
+    let confident = result.as_ref().ok().and_then(Option::as_ref).is_some_and(|decision| decision.confidence >= min_confidence);
+    trace.outcomes.push(Outcome {backend: backend.name(), result});
+    if confident {trace.chosen = Some(trace.outcomes.len() - 1); break}
+  }
+  trace
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use super::*;
+
+  struct Fixed(&'static str, Result<Option<f64>, &'static str>);
+
+  impl DecisionBackend for Fixed {
+    fn name(&self) -> &'static str {self.0}
+
+    fn decide(&self, _process: &Process) -> Result<Option<Decision>, String> {
+      let confidence = self.1.map_err(str::to_string)?;
+      Ok(confidence.map(|confidence| Decision {config: PoliteConfig {niceness: Some(10), ..PoliteConfig::default()}, confidence,
+        explanation: self.0.to_string()}))
+    }
+  }
+
+  fn process() -> Process {
+    Process {path: PathBuf::from("/usr/bin/make"), argv: vec!["make".to_string()], parent: "sh".to_string(), uid: 1000, user: "dev".to_string()}
+  }
+
+  fn chosen(backends: Vec<Fixed>, min_confidence: f64) -> (Option<&'static str>, usize) {
+    let backends: Vec<Box<dyn DecisionBackend>> = backends.into_iter().map(|backend| Box::new(backend) as Box<dyn DecisionBackend>).collect();
+    let trace = decide(&backends, min_confidence, &process());
+    (trace.chosen().map(|(backend, _)| backend), trace.outcomes.len())
+  }
+
+  #[test]
+  fn stops_at_first_confident_backend() {
+    assert_eq!(chosen(vec![Fixed("a", Ok(None)), Fixed("b", Err("down")), Fixed("c", Ok(Some(0.7))), Fixed("d", Ok(Some(1.0)))], 0.5), (Some("c"), 3));
+    assert_eq!(chosen(vec![Fixed("a", Ok(Some(0.5)))], 0.5), (Some("a"), 1));
+  }
+
+  #[test]
+  fn chooses_nothing_below_min_confidence() {
+    assert_eq!(chosen(vec![Fixed("a", Ok(Some(0.4))), Fixed("b", Ok(Some(0.1)))], 0.5), (None, 2));
+    assert_eq!(chosen(vec![], 0.0), (None, 0));
+  }
+
+  #[test]
+  fn restricts_model_answers() {
+    let config = PoliteConfig {niceness: Some(-20), oom_score_adj: Some(-1000), io_class: IoClass::Realtime, io_level: 0, ..PoliteConfig::default()};
+    let restricted = restrict(config, 3);
+    assert_eq!((restricted.niceness, restricted.oom_score_adj, restricted.io_class, restricted.io_level), (Some(3), Some(0), IoClass::BestEffort, 4));
+    let config = PoliteConfig {niceness: Some(15), oom_score_adj: Some(500), io_class: IoClass::Idle, io_level: 7, ..PoliteConfig::default()};
+    assert_eq!(restrict(config.clone(), 3), config);
+    assert_eq!(restrict(PoliteConfig::default(), 3).niceness, None);
+  }
+
+  #[test]
+  fn redacts_argument_values() {
+    let redacted: Vec<String> = ["--password=hunter2", "--verbose", "-pSECRET", "-j8", "-", "secret.txt"].iter().map(|arg| redact(arg)).collect();
+    assert_eq!(redacted, ["--password=<value>", "--verbose", "-p", "-j", "-", "<arg>"]);
+  }
+
+  #[test]
+  fn reads_http_settings() {
+    let mut settings = DecisionSettings::default();
+    let table = "backends = [\"http\", \"rules\"]\nmin_confidence = 1\n[http]\nurl = \"http://127.0.0.1:11434/v1/chat/completions\"\nmodel = \"m\"\ninclude_args = true";
+    settings.update(&table.parse().unwrap()).unwrap();
+    assert_eq!(settings.min_confidence, 1.0);
+    assert!(settings.http.is_some_and(|http| http.include_args && http.timeout == 30));
+    assert!(DecisionSettings::default().update(&"backends = [\"http\"]".parse().unwrap()).is_err());
+    assert!(DecisionSettings::default().update(&"min_confidence = 1.5".parse().unwrap()).is_err());
+    assert!(DecisionSettings::default().update(&"[http]\nurl = \"ftp://host\"\nmodel = \"m\"".parse().unwrap()).is_err());
+  }
+}
@@ -1,142 +1,1062 @@
+mod cgroup;
+mod check;
+mod config;
+mod daemon;
+mod decision;
+mod heuristics;
+mod idle;
+mod online;
+mod power;
+mod pressure;
+mod procfs;
+mod rules;
+mod schedule;
+mod supervise;
+
 use std::process::{Command, Stdio};
+use std::os::unix::process::CommandExt;
+use std::os::unix::fs::PermissionsExt;
 use std::fs::{File, read_to_string};
-use std::io::{BufRead, BufReader};
-use std::path::Path;
-use nix::unistd::{fork, ForkResult, setpgid, Pid};
-use nix::sys::wait::waitpid;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use nix::unistd::{fork, ForkResult, setpgid, getpid, getpgrp, pipe2, tcgetpgrp, tcsetpgrp, Pid};
+use nix::sys::resource::Resource;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::sys::signal::{killpg, raise, sigaction, SigAction, SigHandler, SaFlags, SigSet, Signal};
+use nix::fcntl::OFlag;
+use nix::errno::Errno;
+use nix::libc;
 use std::collections::HashMap;
-use reqwest::blocking::get;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum IoClass {
+  #[default]
+  None,
+  Realtime,
+  BestEffort,
+  Idle
+}
+
+impl std::str::FromStr for IoClass {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "" | "none" | "0" => Ok(IoClass::None),
+      "realtime" | "rt" | "1" => Ok(IoClass::Realtime),
+      "best-effort" | "be" | "2" => Ok(IoClass::BestEffort),
+      "idle" | "3" => Ok(IoClass::Idle),
+      _ => Err(format!("Unknown I/O class {}", s))
+    }
+  }
+}
+
+impl std::fmt::Display for IoClass {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str(match self {
+      IoClass::None => "none",
+      IoClass::Realtime => "realtime",
+      IoClass::BestEffort => "best-effort",
+      IoClass::Idle => "idle"
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum SchedPolicy {
+  #[default]
+  Unchanged,
+  Other,
+  Batch,
+  Idle,
+  Unknown(i32)
+}
+
+impl SchedPolicy {
+  fn from_raw(policy: i32) -> Self {
+    match policy {
+      libc::SCHED_OTHER => SchedPolicy::Other,
+      libc::SCHED_BATCH => SchedPolicy::Batch,
+      libc::SCHED_IDLE => SchedPolicy::Idle,
+      _ => SchedPolicy::Unknown(policy)
+    }
+  }
+
+  fn as_raw(&self) -> Option<i32> {
+    match self {
+      SchedPolicy::Unchanged => None,
+      SchedPolicy::Other => Some(libc::SCHED_OTHER),
+      SchedPolicy::Batch => Some(libc::SCHED_BATCH),
+      SchedPolicy::Idle => Some(libc::SCHED_IDLE),
+      SchedPolicy::Unknown(policy) => Some(*policy)
+    }
+  }
+}
+
+impl std::fmt::Display for SchedPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      SchedPolicy::Unchanged => f.write_str("unchanged"),
+      SchedPolicy::Other => f.write_str("other"),
+      SchedPolicy::Batch => f.write_str("batch"),
+      SchedPolicy::Idle => f.write_str("idle"),
+      SchedPolicy::Unknown(policy) => write!(f, "policy {}", policy)
+    }
+  }
+}
+
+fn parse_sched_policy(s: &str) -> Result<(SchedPolicy, bool), String> {
+  let (name, reset_on_fork) = match s.strip_suffix("+reset") {
+    Some(name) => (name, true),
+    None => (s, false)
+  };
+  let policy = match name {
+    "" => SchedPolicy::Unchanged,
+    "other" | "normal" => SchedPolicy::Other,
+    "batch" => SchedPolicy::Batch,
+    "idle" => SchedPolicy::Idle,
+    _ => return Err(format!("Unknown scheduling policy {}", s))
+  };
+  Ok((policy, reset_on_fork))
+}
+
+fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+  let mut cpus = Vec::new();
+  for range in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start = start.trim().parse::<usize>().map_err(|e| format!("Invalid CPU list {}: {}", s, e))?;
+    let end = end.trim().parse::<usize>().map_err(|e| format!("Invalid CPU list {}: {}", s, e))?;
+    if start > end {return Err(format!("Invalid CPU list {}: {} > {}", s, start, end))}
+    cpus.extend(start..=end);
+  }
+  if cpus.is_empty() {return Err(format!("Empty CPU list {}", s))}
+  cpus.sort_unstable();
+  cpus.dedup();
+  Ok(cpus)
+}
 
-fn mock_llm_decision(program: &str) -> PoliteConfig {
-  println!("LLM deciding for {}...", program);
-  PoliteConfig {
-    niceness: if program.contains("boinc") {5} else {0},
-    oom_score_adj: 100
+fn format_cpu_list(cpus: &[usize]) -> String {
+  let mut ranges: Vec<String> = Vec::new();
+  let mut i = 0;
+  while i < cpus.len() {
+    let start = cpus[i];
+    while i + 1 < cpus.len() && cpus[i + 1] == cpus[i] + 1 {i += 1}
+    ranges.push(if cpus[i] == start {start.to_string()} else {format!("{}-{}", start, cpus[i])});
+    i += 1;
   }
+  ranges.join(",")
 }
 
-#[derive(Debug, Clone)]
+fn parse_size(value: &str) -> Result<u64, String> {
+  let (digits, multiplier) = match value.char_indices().last() {
+    Some((i, 'K')) | Some((i, 'k')) => (&value[..i], 1 << 10),
+    Some((i, 'M')) | Some((i, 'm')) => (&value[..i], 1 << 20),
+    Some((i, 'G')) | Some((i, 'g')) => (&value[..i], 1 << 30),
+    Some((i, 'T')) | Some((i, 't')) => (&value[..i], 1 << 40),
+    _ => (value, 1)
+  };
+  let size = digits.parse::<u64>().map_err(|e| format!("Invalid size {}: {}", value, e))?;
+  size.checked_mul(multiplier).ok_or_else(|| format!("Size {} is too large", value))
+}
+
+const RLIMITS: [(&str, Resource, &str); 5] = [
+  ("as", Resource::RLIMIT_AS, "Max address space"),
+  ("core", Resource::RLIMIT_CORE, "Max core file size"),
+  ("cpu", Resource::RLIMIT_CPU, "Max cpu time"),
+  ("nofile", Resource::RLIMIT_NOFILE, "Max open files"),
+  ("nproc", Resource::RLIMIT_NPROC, "Max processes")
+];
+
+fn parse_rlimit_value(value: &str) -> Result<u64, String> {
+  match value {
+    "unlimited" | "infinity" => Ok(libc::RLIM_INFINITY),
+    _ => parse_size(value)
+  }
+}
+
+fn format_rlimit_value(value: u64) -> String {
+  if value == libc::RLIM_INFINITY {"unlimited".to_string()} else {value.to_string()}
+}
+
+fn parse_rlimit(value: &str) -> Result<(u64, u64), String> {
+  let (soft, hard) = value.split_once(':').unwrap_or((value, value));
+  let (soft, hard) = (parse_rlimit_value(soft.trim())?, parse_rlimit_value(hard.trim())?);
+  if soft > hard {return Err(format!("Soft limit exceeds hard limit in {}", value))}
+  Ok((soft, hard))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct NumaPolicy {
+  mode: libc::c_int,
+  nodes: Vec<usize>
+}
+
+impl std::str::FromStr for NumaPolicy {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    let (mode, nodes) = s.split_once(':').ok_or_else(|| format!("Invalid NUMA policy {}, expected <mode>:<nodes>", s))?;
+    let mode = match mode {
+      "preferred" => MPOL_PREFERRED,
+      "bind" => MPOL_BIND,
+      "interleave" => MPOL_INTERLEAVE,
+      _ => return Err(format!("Unknown NUMA mode {}", mode))
+    };
+    Ok(NumaPolicy {mode, nodes: parse_cpu_list(nodes)?})
+  }
+}
+
+impl std::fmt::Display for NumaPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let mode = match self.mode {
+      MPOL_PREFERRED => "preferred",
+      MPOL_BIND => "bind",
+      _ => "interleave"
+    };
+    write!(f, "{}:{}", mode, format_cpu_list(&self.nodes))
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 struct PoliteConfig {
-  niceness: i32,
-  oom_score_adj: i32
+  niceness: Option<i32>,
+  oom_score_adj: Option<i32>,
+  io_class: IoClass,
+  io_level: i32,
+  sched_policy: SchedPolicy,
+  sched_reset_on_fork: bool,
+  cpus: Option<Vec<usize>>,
+  numa: Option<NumaPolicy>,
+  rlimits: Vec<(&'static str, u64, u64)>,
+  cgroup: cgroup::CgroupLimits,
+  adaptive: Option<pressure::AdaptivePolicy>,
+  idle: Option<idle::IdlePolicy>,
+  schedule: Vec<schedule::Window>,
+  power: Option<power::PowerPolicy>
+}
+
+fn normalize_alias(name: &str) -> Result<String, String> {
+  if let Ok(number) = name.parse::<i64>() {return Ok(number.to_string())}
+  if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+    return Err(format!("Invalid alias name {:?}", name))
+  }
+  Ok(name.to_string())
+}
+
+fn is_reserved_alias_line(line: &str) -> bool {
+  normalize_alias(line.split(';').next().unwrap_or("").trim()).as_deref() == Ok("0")
+}
+
+struct FieldError {
+  field: usize,
+  message: String
 }
 
-fn parse_config_line(line: &str) -> Result<(i8, PoliteConfig), String> {
-  let parts: Vec<&str> = line.split(';').collect();
-  if parts.len() < 3 {return Err("Invalid config".to_string())}
-  let alias: i8 = parts[0].parse().map_err(|e| e.to_string())?;
-  if alias == 0 {return Err("Alias 0 reserved".to_string())}
-  let niceness: i32 = parts[1].parse().map_err(|e| e.to_string())?;
-  let oom_score_adj: i32 = parts[2].parse().map_err(|e| e.to_string())?;
-  if niceness < -20 || niceness > 19 || oom_score_adj < -1000 || oom_score_adj > 1000 {
-    return Err("Value out of range".to_string())
+fn parse_config_fields(line: &str) -> Result<(String, PoliteConfig), FieldError> {
+  let error = |field: usize| move |message: String| FieldError {field, message};
+  let parts: Vec<&str> = line.split(';').map(str::trim).collect();
+  if parts.len() < 3 {
+    return Err(error(parts.len())(format!("expected at least 3 fields (alias;niceness;oom_score_adj), found {}", parts.len())))
+  }
+  let alias = normalize_alias(parts[0]).map_err(error(0))?;
+  if alias == "0" {return Err(error(0)("Alias 0 reserved".to_string()))}
+  let niceness = parse_optional("niceness", parts[1], NICENESS_RANGE).map_err(error(1))?;
+  let oom_score_adj = parse_optional("oom_score_adj", parts[2], OOM_SCORE_ADJ_RANGE).map_err(error(2))?;
+  let io_class: IoClass = parts.get(3).copied().unwrap_or("").parse().map_err(error(3))?;
+  let io_level = match parts.get(4) {
+    Some(level) if !level.is_empty() => parse_ranged("io_level", level, IO_LEVEL_RANGE).map_err(error(4))?,
+    _ => 4
+  };
+  let (sched_policy, sched_reset_on_fork) = parse_sched_policy(parts.get(5).copied().unwrap_or("")).map_err(error(5))?;
+  let mut config = PoliteConfig {niceness, oom_score_adj, io_class, io_level, sched_policy, sched_reset_on_fork, ..PoliteConfig::default()};
+  for (field, setting) in parts.iter().enumerate().skip(6).filter(|(_, p)| !p.is_empty()) {
+    let (key, value) = setting.split_once('=').ok_or_else(|| format!("Invalid setting {}, expected key=value", setting)).map_err(error(field))?;
+    apply_setting(&mut config, key.trim(), value.trim()).map_err(error(field))?;
   }
-  Ok((alias, PoliteConfig {niceness, oom_score_adj}))
+  validate_config(&config).map_err(error(0))?;
+  Ok((alias, config))
 }
 
-fn load_local_config(file_path: &str) -> Result<HashMap<i8, PoliteConfig>, String> {
+fn parse_config_line(line: &str) -> Result<(String, PoliteConfig), String> {
+  parse_config_fields(line).map_err(|e| e.message)
+}
+
+const NICENESS_RANGE: RangeInclusive<i32> = -20..=19;
+const OOM_SCORE_ADJ_RANGE: RangeInclusive<i32> = -1000..=1000;
+const IO_LEVEL_RANGE: RangeInclusive<i32> = 0..=7;
+
+fn check_range(name: &str, value: i32, range: RangeInclusive<i32>) -> Result<i32, String> {
+  if range.contains(&value) {return Ok(value)}
+  Err(format!("{} {} out of range, allowed {} to {}", name, value, range.start(), range.end()))
+}
+
+fn parse_ranged(name: &str, value: &str, range: RangeInclusive<i32>) -> Result<i32, String> {
+  let number = value.parse::<i32>().map_err(|e| format!("{} {:?}: {}", name, value, e))?;
+  check_range(name, number, range)
+}
+
+fn parse_optional(name: &str, value: &str, range: RangeInclusive<i32>) -> Result<Option<i32>, String> {
+  if value.is_empty() {Ok(None)} else {parse_ranged(name, value, range).map(Some)}
+}
+
+fn format_optional(value: Option<i32>) -> String {
+  value.map_or("unchanged".to_string(), |value| value.to_string())
+}
+
+fn apply_setting(config: &mut PoliteConfig, key: &str, value: &str) -> Result<(), String> {
+  match key {
+    "cpus" => config.cpus = Some(parse_cpu_list(value)?),
+    "numa" => config.numa = Some(value.parse()?),
+    key if key.starts_with("adaptive.") => config.adaptive.get_or_insert_default().set(&key["adaptive.".len()..], value)?,
+    "on_battery" | "min_charge" => config.power.get_or_insert_default().set(key, value)?,
+    key if key.starts_with("idle.") => config.idle.get_or_insert_default().set(&key["idle.".len()..], value)?,
+    key => match RLIMITS.iter().find(|(name, _, _)| *name == key) {
+      Some((name, _, _)) => {
+        let (soft, hard) = parse_rlimit(value).map_err(|e| format!("{}: {}", name, e))?;
+        config.rlimits.retain(|(n, _, _)| n != name);
+        config.rlimits.push((*name, soft, hard));
+      }
+      None if key.contains('.') => config.cgroup.set(key, value)?,
+      None => return Err(format!("Unknown setting {}", key))
+    }
+  }
+  Ok(())
+}
+
+fn validate_config(config: &PoliteConfig) -> Result<(), String> {
+  if let Some(niceness) = config.niceness {check_range("niceness", niceness, NICENESS_RANGE)?;}
+  if let Some(oom_score_adj) = config.oom_score_adj {check_range("oom_score_adj", oom_score_adj, OOM_SCORE_ADJ_RANGE)?;}
+  check_range("io_level", config.io_level, IO_LEVEL_RANGE)?;
+  if config.sched_reset_on_fork && config.sched_policy == SchedPolicy::Unchanged {
+    return Err("+reset needs a scheduling policy".to_string())
+  }
+  if let Some(adaptive) = &config.adaptive {adaptive.validate()?}
+  Ok(())
+}
+
+fn load_local_config(file_path: &str, loaded: &mut config::LoadedConfig) -> Result<(), String> {
+  if file_path.ends_with(".toml") {config::load_toml_config(file_path, loaded)} else {
+    load_legacy_config(file_path)
+      .map(|aliases| loaded.aliases.extend(aliases.into_iter().map(|(alias, config)| (alias, (config, file_path.to_string())))))
+  }.map_err(|e| format!("{}: {}", file_path, e))
+}
+
+fn load_legacy_config(file_path: &str) -> Result<HashMap<String, PoliteConfig>, String> {
   let file = File::open(file_path).map_err(|e| e.to_string())?;
   let reader = BufReader::new(file);
   let mut configs = HashMap::new();
   let mut in_section = false;
-  for line in reader.lines() {
+  for (number, line) in reader.lines().enumerate() {
     let line = line.map_err(|e| e.to_string())?.trim().to_string();
     if line == "-START-" {in_section = true; continue}
     if line == "-END-" {break}
-    if in_section && !line.is_empty() && !line.starts_with('#') {
-      let (alias, config) = parse_config_line(&line)?;
+    if in_section && !line.is_empty() && !line.starts_with('#') && !is_reserved_alias_line(&line) {
+      let (alias, config) = parse_config_line(&line).map_err(|e| format!("line {}: {}", number + 1, e))?;
       configs.insert(alias, config);
     }
   }
   Ok(configs)
 }
 
-fn fetch_online_config() -> Result<HashMap<i8, PoliteConfig>, String> {
-  let url = "https://raw.githubusercontent.com/username/repo/main/polite_config.csv";
-  let text = get(url).map_err(|e| format!("Fetch error: {}", e))?.text().map_err(|e| e.to_string())?;
-  let mut configs = HashMap::new();
-  for line in text.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#')) {
-    if let Ok((alias, config)) = parse_config_line(line) {
-      configs.insert(alias, config);
-    }
+fn config_search_path(config_override: Option<&str>) -> Vec<(PathBuf, bool)> {
+  let mut dirs = vec![PathBuf::from("/etc/polite")];
+  match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+    Some(dir) => dirs.push(PathBuf::from(dir).join("polite")),
+    None => if let Some(home) = std::env::var_os("HOME") {dirs.push(PathBuf::from(home).join(".config/polite"))}
   }
-  if configs.is_empty() {Err("No valid online configs".to_string())} else {Ok(configs)}
+  dirs.push(PathBuf::from("."));
+  let mut files: Vec<(PathBuf, bool)> = dirs.iter()
+    .flat_map(|dir| ["polite.conf", "polite.toml"].map(|name| (dir.join(name), false))).collect();
+  if let Some(path) = std::env::var_os("POLITE_CONFIG").filter(|path| !path.is_empty()) {files.push((PathBuf::from(path), true))}
+  if let Some(path) = config_override {files.push((PathBuf::from(path), true))}
+  files
+}
+
+fn load_configs(config_override: Option<&str>) -> Result<config::LoadedConfig, String> {
+  let mut configs = config::LoadedConfig::default();
+  for (path, required) in config_search_path(config_override) {
+    if !required && !path.exists() {continue}
+    load_local_config(&path.display().to_string(), &mut configs)?;
+  }
+  Ok(configs)
+}
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+const MPOL_PREFERRED: libc::c_int = 1;
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_INTERLEAVE: libc::c_int = 3;
+
+fn set_io_priority(pid: Pid, io_class: IoClass, io_level: i32) -> Result<(), String> {
+  let ioprio = (io_class as libc::c_long) << IOPRIO_CLASS_SHIFT | io_level as libc::c_long;
+  if unsafe {libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, pid.as_raw(), ioprio)} == -1 {
+    return Err(format!("Ionice error: {}", Errno::last()))
+  }
+  Ok(())
 }
 
 fn apply_runtime_settings(pid: Pid, config: &PoliteConfig) -> Result<(), String> {
-  nix::unistd::setpriority(nix::unistd::Priority::Process(pid.into()), config.niceness)
-    .map_err(|e| format!("Niceness error: {}", e))?;
-  std::fs::write(format!("/proc/{}/oom_score_adj", pid), config.oom_score_adj.to_string())
-    .map_err(|e| format!("OOM error: {}", e))?;
+  if let Some(niceness) = config.niceness {
+    if unsafe {libc::setpriority(libc::PRIO_PROCESS, pid.as_raw() as libc::id_t, niceness)} == -1 {
+      return Err(format!("Niceness error: {}", Errno::last()))
+    }
+  }
+  if let Some(policy) = config.sched_policy.as_raw() {
+    let flags = if config.sched_reset_on_fork {libc::SCHED_RESET_ON_FORK} else {0};
+    let param = libc::sched_param {sched_priority: 0};
+    if unsafe {libc::sched_setscheduler(pid.as_raw(), policy | flags, &param)} == -1 {
+      return Err(format!("Scheduler error: {}", Errno::last()))
+    }
+  }
+  if let Some(oom_score_adj) = config.oom_score_adj {
+    std::fs::write(format!("/proc/{}/oom_score_adj", pid), oom_score_adj.to_string()).map_err(|e| format!("OOM error: {}", e))?;
+  }
+  if config.io_class != IoClass::None {set_io_priority(pid, config.io_class, config.io_level)?}
+  if let Some(cpus) = &config.cpus {
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus {cpu_set.set(*cpu).map_err(|e| format!("Affinity error: CPU {}: {}", cpu, e))?}
+    sched_setaffinity(pid, &cpu_set).map_err(|e| format!("Affinity error: {}", e))?;
+  }
+  for (name, soft, hard) in &config.rlimits {
+    let (_, resource, _) = RLIMITS.iter().find(|(n, _, _)| n == name).ok_or_else(|| format!("Unknown rlimit {}", name))?;
+    let limit = libc::rlimit {rlim_cur: *soft, rlim_max: *hard};
+    if unsafe {libc::prlimit(pid.as_raw(), *resource as _, &limit, std::ptr::null_mut())} == -1 {
+      return Err(format!("Rlimit error: {}: {}", name, Errno::last()))
+    }
+  }
+  if let Some(numa) = &config.numa {
+    if pid != getpid() {return Err(format!("NUMA error: policy can only be set by PID {} itself", pid))}
+    let mut mask: Vec<libc::c_ulong> = vec![0; numa.nodes.iter().max().map_or(1, |n| n / 64 + 1)];
+    for node in &numa.nodes {mask[node / 64] |= 1 << (node % 64)}
+    let max_node = (mask.len() * 64 + 1) as libc::c_ulong;
+    if unsafe {libc::syscall(libc::SYS_set_mempolicy, numa.mode, mask.as_ptr(), max_node)} == -1 {
+      return Err(format!("NUMA error: {}", Errno::last()))
+    }
+  }
   Ok(())
 }
 
-fn get_applied_settings(pid: Pid) -> Result<String, String> {
-  let nice = nix::unistd::getpriority(nix::unistd::Priority::Process(pid.into()))
-    .map_err(|e| format!("Get nice error: {}", e))?;
-  let oom = read_to_string(format!("/proc/{}/oom_score_adj", pid))
-    .map_err(|e| format!("Get oom error: {}", e))?.trim().to_string();
-  Ok(format!("PID {}: niceness={}, oom_score_adj={}", pid, nice, oom))
+fn get_applied_settings(pid: Pid) -> Result<PoliteConfig, String> {
+  Errno::clear();
+  let niceness = unsafe {libc::getpriority(libc::PRIO_PROCESS, pid.as_raw() as libc::id_t)};
+  if niceness == -1 && Errno::last_raw() != 0 {return Err(format!("Get nice error: {}", Errno::last()))}
+  let oom_score_adj = read_to_string(format!("/proc/{}/oom_score_adj", pid))
+    .map_err(|e| format!("Get oom error: {}", e))?.trim().parse().map_err(|e| format!("Get oom error: {}", e))?;
+  let ioprio = unsafe {libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, pid.as_raw())};
+  if ioprio == -1 {return Err(format!("Get ionice error: {}", Errno::last()))}
+  let io_class = (ioprio >> IOPRIO_CLASS_SHIFT).to_string().parse()?;
+  let io_level = (ioprio & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as i32;
+  let policy = unsafe {libc::sched_getscheduler(pid.as_raw())};
+  if policy == -1 {return Err(format!("Get scheduler error: {}", Errno::last()))}
+  let sched_policy = SchedPolicy::from_raw(policy & !libc::SCHED_RESET_ON_FORK);
+  let sched_reset_on_fork = policy & libc::SCHED_RESET_ON_FORK != 0;
+  let status = read_to_string(format!("/proc/{}/status", pid)).map_err(|e| format!("Get affinity error: {}", e))?;
+  let cpus = status.lines().find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+    .map(|list| parse_cpu_list(list.trim())).transpose()?;
+  let limits = read_to_string(format!("/proc/{}/limits", pid)).map_err(|e| format!("Get rlimit error: {}", e))?;
+  let mut rlimits = Vec::new();
+  for (name, _, label) in RLIMITS {
+    let values: Vec<&str> = limits.lines().find_map(|line| line.strip_prefix(label))
+      .ok_or_else(|| format!("Get rlimit error: {} missing", label))?.split_whitespace().collect();
+    if values.len() < 2 {return Err(format!("Get rlimit error: malformed {}", label))}
+    rlimits.push((name, parse_rlimit_value(values[0])?, parse_rlimit_value(values[1])?));
+  }
+  Ok(PoliteConfig {niceness: Some(niceness), oom_score_adj: Some(oom_score_adj), io_class, io_level, sched_policy, sched_reset_on_fork, cpus, rlimits,
+    ..PoliteConfig::default()})
+}
+
+fn inherit_settings(config: &mut PoliteConfig, started: &PoliteConfig) {
+  config.niceness = config.niceness.or(started.niceness);
+  config.oom_score_adj = config.oom_score_adj.or(started.oom_score_adj);
+  if config.io_class == IoClass::None {(config.io_class, config.io_level) = (started.io_class, started.io_level)}
+  if config.sched_policy == SchedPolicy::Unchanged {
+    (config.sched_policy, config.sched_reset_on_fork) = (started.sched_policy, started.sched_reset_on_fork);
+  }
+  if config.cpus.is_none() {config.cpus = started.cpus.clone()}
+}
+
+fn verify_applied_settings(pid: Pid, config: &PoliteConfig) -> Result<(), String> {
+  let applied = get_applied_settings(pid)?;
+  if config.niceness.is_some() && applied.niceness != config.niceness {
+    return Err(format!("PID {}: niceness is {}, expected {}", pid, format_optional(applied.niceness), format_optional(config.niceness)))
+  }
+  if config.oom_score_adj.is_some() && applied.oom_score_adj != config.oom_score_adj {
+    return Err(format!("PID {}: oom_score_adj is {}, expected {}", pid, format_optional(applied.oom_score_adj),
+      format_optional(config.oom_score_adj)))
+  }
+  if config.io_class != IoClass::None && (applied.io_class != config.io_class
+    || (config.io_class != IoClass::Idle && applied.io_level != config.io_level)) {
+    return Err(format!("PID {}: ionice is {}:{}, expected {}:{}", pid, applied.io_class, applied.io_level, config.io_class, config.io_level))
+  }
+  if config.sched_policy != SchedPolicy::Unchanged
+    && (applied.sched_policy != config.sched_policy || applied.sched_reset_on_fork != config.sched_reset_on_fork) {
+    return Err(format!("PID {}: scheduling policy is {}, expected {}", pid, applied.sched_policy, config.sched_policy))
+  }
+  if let Some(cpus) = &config.cpus {
+    if applied.cpus.as_ref() != Some(cpus) {
+      let applied_cpus = applied.cpus.as_deref().map(format_cpu_list).unwrap_or_default();
+      return Err(format!("PID {}: CPU affinity is {}, expected {}", pid, applied_cpus, format_cpu_list(cpus)))
+    }
+  }
+  for (name, soft, hard) in &config.rlimits {
+    if let Some((_, applied_soft, applied_hard)) = applied.rlimits.iter().find(|(n, _, _)| n == name) {
+      if (applied_soft, applied_hard) != (soft, hard) {
+        return Err(format!("PID {}: {} limit is {}:{}, expected {}:{}", pid, name, format_rlimit_value(*applied_soft),
+          format_rlimit_value(*applied_hard), format_rlimit_value(*soft), format_rlimit_value(*hard)))
+      }
+    }
+  }
+  Ok(())
+}
+
+fn apply_to_pid(pid: Pid, config: &PoliteConfig, job_cgroup: Option<&cgroup::Cgroup>) -> Result<(), String> {
+  if let Some(job_cgroup) = job_cgroup {job_cgroup.add_process(pid)?}
+  apply_runtime_settings(pid, config)?;
+  verify_applied_settings(pid, config)
+}
+
+fn verify_job(pid: Pid, job_cgroup: Option<&cgroup::Cgroup>, cgroup_root: &Path) -> Result<(), String> {
+  if let Some(job_cgroup) = job_cgroup {
+    let current = cgroup::Cgroup::for_pid(cgroup_root, pid)?;
+    if current.name() != job_cgroup.name() {
+      return Err(format!("PID {}: cgroup is {}, expected {}", pid, current.name(), job_cgroup.name()))
+    }
+  }
+  Ok(())
+}
+
+fn describe_config(config: &PoliteConfig) -> String {
+  let mut description = format!("niceness={}, oom_score_adj={}, io_class={}, io_level={}, sched_policy={}{}", format_optional(config.niceness),
+    format_optional(config.oom_score_adj), config.io_class, config.io_level, config.sched_policy, if config.sched_reset_on_fork {"+reset"} else {""});
+  if let Some(cpus) = &config.cpus {description.push_str(&format!(", cpus={}", format_cpu_list(cpus)))}
+  if let Some(numa) = &config.numa {description.push_str(&format!(", numa={}", numa))}
+  for (name, soft, hard) in &config.rlimits {
+    description.push_str(&format!(", {}={}:{}", name, format_rlimit_value(*soft), format_rlimit_value(*hard)))
+  }
+  if !config.cgroup.is_empty() {description.push_str(&format!(", cgroup: {}", config.cgroup))}
+  if let Some(adaptive) = &config.adaptive {description.push_str(&format!(", adaptive: {}", adaptive))}
+  if let Some(idle) = &config.idle {description.push_str(&format!(", idle: {}", idle))}
+  if let Some(power) = &config.power {description.push_str(&format!(", power: {}", power))}
+  if !config.schedule.is_empty() {
+    let windows: Vec<String> = config.schedule.iter().map(|window| window.to_string()).collect();
+    description.push_str(&format!(", schedule: {}", windows.join(", ")))
+  }
+  description
+}
+
+struct RunArgs {
+  alias: String,
+  program: String,
+  args: Vec<String>,
+  env: Vec<(String, Option<String>)>,
+  clear_env: bool,
+  cwd: Option<String>
+}
+
+struct ApplyArgs {
+  alias: String,
+  targets: Vec<Pid>,
+  threads: bool
+}
+
+fn parse_pid(option: &str, value: Option<&String>) -> Result<Pid, String> {
+  let value = value.ok_or_else(|| format!("{} needs a PID", option))?;
+  value.parse().map(Pid::from_raw).map_err(|_| format!("Invalid PID {}", value))
+}
+
+fn parse_apply_args(args: &[String]) -> Result<ApplyArgs, String> {
+  let mut iter = args.iter();
+  let alias = normalize_alias(iter.next().ok_or("Missing alias")?)?;
+  let mut apply = ApplyArgs {alias, targets: Vec::new(), threads: false};
+  while let Some(arg) = iter.next() {
+    match arg.as_str() {
+      "-t" | "--threads" => apply.threads = true,
+      "--name" => {
+        let pattern = iter.next().ok_or("--name needs a pattern")?;
+        let pattern = glob::Pattern::new(pattern).map_err(|e| format!("Invalid --name pattern {}: {}", pattern, e))?;
+        apply.targets.extend(procfs::pids().into_iter().filter(|pid| procfs::comm(*pid).is_some_and(|comm| pattern.matches(&comm))));
+      }
+      "--pgid" => apply.targets.extend(procfs::process_group(parse_pid(arg, iter.next())?)),
+      "--tree" => apply.targets.extend(procfs::tree(parse_pid(arg, iter.next())?)),
+      _ if !arg.starts_with('-') => apply.targets.push(parse_pid(arg, Some(arg))?),
+      _ => return Err(format!("Unknown apply option: {}", arg))
+    }
+  }
+  apply.targets.retain(|pid| *pid != getpid());
+  apply.targets.sort();
+  apply.targets.dedup();
+  if apply.targets.is_empty() {return Err("No matching processes".to_string())}
+  Ok(apply)
+}
+
+fn parse_run_args(args: &[String]) -> Result<RunArgs, String> {
+  let mut iter = args.iter();
+  let alias = normalize_alias(iter.next().ok_or("Missing alias")?)?;
+  let mut run = RunArgs {alias, program: String::new(), args: Vec::new(), env: Vec::new(), clear_env: false, cwd: None};
+  while let Some(arg) = iter.next() {
+    match arg.as_str() {
+      "--" => {run.program = iter.next().ok_or("Missing program after --")?.clone(); break}
+      "-e" | "--env" => {
+        let pair = iter.next().ok_or("--env needs KEY=VALUE")?;
+        let (key, value) = pair.split_once('=').ok_or_else(|| format!("Invalid --env {}, expected KEY=VALUE", pair))?;
+        run.env.push((key.to_string(), Some(value.to_string())));
+      }
+      "-u" | "--unset-env" => run.env.push((iter.next().ok_or("--unset-env needs KEY")?.clone(), None)),
+      "-i" | "--clear-env" => run.clear_env = true,
+      "-C" | "--chdir" => run.cwd = Some(iter.next().ok_or("--chdir needs DIR")?.clone()),
+      _ if !arg.starts_with('-') => {run.program = arg.clone(); break}
+      _ => return Err(format!("Unknown run option: {}", arg))
+    }
+  }
+  if run.program.is_empty() {return Err("Missing program".to_string())}
+  run.args = iter.cloned().collect();
+  Ok(run)
+}
+
+impl RunArgs {
+  fn argv(&self) -> Vec<String> {
+    std::iter::once(self.program.clone()).chain(self.args.iter().cloned()).collect()
+  }
+}
+
+fn find_program(run: &RunArgs) -> Result<PathBuf, String> {
+  let is_executable = |p: &Path| p.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false);
+  let program = if run.program.contains('/') {
+    let candidate = run.cwd.as_ref().map(|dir| Path::new(dir).join(&run.program)).unwrap_or_else(|| PathBuf::from(&run.program));
+    if is_executable(&candidate) {candidate} else {return Err(format!("Program {} not found", run.program))}
+  } else {
+    let path = match run.env.iter().rev().find(|(key, _)| key == "PATH") {
+      Some((_, value)) => value.clone(),
+      None if run.clear_env => None,
+      None => std::env::var("PATH").ok()
+    }.unwrap_or_else(|| "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string());
+    path.split(':').map(|dir| Path::new(if dir.is_empty() {"."} else {dir}).join(&run.program))
+      .find(|p| is_executable(p)).ok_or_else(|| format!("Program {} not found in PATH", run.program))?
+  };
+  if program.is_absolute() {return Ok(program)}
+  std::env::current_dir().map(|dir| dir.join(program)).map_err(|e| format!("Program {}: current directory: {}", run.program, e))
+}
+
+struct Resolution {
+  ruleset: Result<online::Ruleset, String>,
+  backends: Vec<&'static str>,
+  trace: decision::Trace
+}
+
+fn resolve_alias(configs: &config::LoadedConfig, path: &Path, run: &RunArgs) -> (Resolution, PoliteConfig) {
+  let ruleset = online::load(&configs.online);
+  let process = rules::Process::for_launch(path, run.argv());
+  let (backends, trace) = {
+    let backends = decision::backends(configs, ruleset.as_ref().ok());
+    let trace = decision::decide(&backends, configs.decision.min_confidence, &process);
+    (backends.iter().map(|backend| backend.name()).collect(), trace)
+  };
+  let config = trace.chosen().map_or_else(PoliteConfig::default, |(_, decision)| decision.config.clone());
+  (Resolution {ruleset, backends, trace}, config)
+}
+
+fn build_command(run: &RunArgs, program_path: &Path) -> Command {
+  let mut command = Command::new(program_path);
+  command.arg0(&run.program).args(&run.args).stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit());
+  if run.clear_env {command.env_clear();}
+  command.env("POLITE_ALIAS", &run.alias);
+  for (key, value) in &run.env {
+    match value {
+      Some(value) => command.env(key, value),
+      None => command.env_remove(key)
+    };
+  }
+  if let Some(dir) = &run.cwd {command.current_dir(dir);}
+  command
+}
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+  let pgid = CHILD_PGID.load(Ordering::SeqCst);
+  if pgid > 0 {
+    supervise::TERMINATING.store(true, Ordering::SeqCst);
+    unsafe {
+      libc::kill(-pgid, signal);
+      libc::kill(-pgid, libc::SIGCONT);
+    }
+  }
+}
+
+fn install_signal_forwarding() -> Result<(), String> {
+  let action = SigAction::new(SigHandler::Handler(forward_signal), SaFlags::SA_RESTART, SigSet::empty());
+  for signal in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGQUIT] {
+    unsafe {sigaction(signal, &action)}.map_err(|e| format!("Signal error: {}", e))?;
+  }
+  Ok(())
+}
+
+fn owns_terminal(pgid: Pid) -> bool {
+  tcgetpgrp(std::io::stdin()).is_ok_and(|foreground| foreground == pgid)
+}
+
+fn give_terminal(pgid: Pid) -> Result<(), String> {
+  let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+  let previous = unsafe {sigaction(Signal::SIGTTOU, &ignore)}.map_err(|e| format!("Terminal error: {}", e))?;
+  let result = tcsetpgrp(std::io::stdin(), pgid).map_err(|e| format!("Terminal error: {}", e));
+  let _ = unsafe {sigaction(Signal::SIGTTOU, &previous)};
+  result
+}
+
+fn job_stopped(child: Pid) {
+  let terminal = owns_terminal(child);
+  if terminal {let _ = give_terminal(getpgrp());}
+  let _ = raise(Signal::SIGSTOP);
+  if terminal && owns_terminal(getpgrp()) {let _ = give_terminal(child);}
+  let _ = killpg(child, Signal::SIGCONT);
+}
+
+fn wait_for_child(child: Pid) -> Result<i32, String> {
+  loop {
+    match waitpid(child, Some(WaitPidFlag::WUNTRACED)) {
+      Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+      Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+      Ok(WaitStatus::Stopped(_, Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU)) => job_stopped(child),
+      Ok(_) | Err(Errno::EINTR) => continue,
+      Err(e) => return Err(format!("Wait error: {}", e))
+    }
+  }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let args: Vec<String> = std::env::args().collect();
+  let mut args: Vec<String> = std::env::args().collect();
+  let config_override = if args.get(1).map(String::as_str) == Some("--config") && args.len() > 2 {
+    let path = args.remove(2);
+    args.remove(1);
+    Some(path)
+  } else {None};
   if args.len() < 2 {
-    eprintln!("Usage: polite <command> [args]");
-    eprintln!("Commands: run <alias> <program>, status <pid>, list");
+    eprintln!("Usage: polite [--config <file>] <command> [args]");
+    eprintln!("Commands: run <alias> [options] -- <program> [args...], explain [alias] -- <program> [args...], apply <alias> <pid...|--name pattern|--pgid pgid|--tree pid>, daemon, status <pid>, list, check [file...], update, config convert [input] [output]");
     std::process::exit(1);
   }
   let command = &args[1];
   match command.as_str() {
     "run" => {
-      if args.len() != 4 {eprintln!("Usage: polite run <alias> <program>"); std::process::exit(1);}
-      let alias: i8 = args[2].parse()?;
-      let program = &args[3];
-      let local_config_file = "polite.conf";
-      let config = if alias == 0 {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        let last_fetch = 0; // Replace with persistent storage
-        if now - last_fetch > 3600 {
-          match fetch_online_config() {
-            Ok(online_configs) => online_configs.get(&65).cloned().unwrap_or_else(|| mock_llm_decision(program)),
-            Err(_) => mock_llm_decision(program)
-          }
-        } else {
-          mock_llm_decision(program)
+      let run = match parse_run_args(&args[2..]) {
+        Ok(run) => run,
+        Err(e) => {
+          eprintln!("{}", e);
+          eprintln!("Usage: polite run <alias> [options] -- <program> [args...]");
+          eprintln!("Options: -e|--env KEY=VALUE, -u|--unset-env KEY, -i|--clear-env, -C|--chdir DIR");
+          std::process::exit(1);
+        }
+      };
+      let alias = &run.alias;
+      let program = &run.program;
+      let configs = load_configs(config_override.as_deref())?;
+      let path = find_program(&run)?;
+      let config = if alias == "0" {
+        let (resolution, config) = resolve_alias(&configs, &path, &run);
+        match &resolution.ruleset {
+          Ok(ruleset) => if let Some(e) = &ruleset.stale {eprintln!("{}, using cached ruleset fetched at {}", e, ruleset.fetched)},
+          Err(e) => if !configs.online.sources.is_empty() {eprintln!("{}", e)}
         }
+        for outcome in &resolution.trace.outcomes {
+          if let Err(e) = &outcome.result {eprintln!("{}: {}", outcome.backend, e)}
+        }
+        match resolution.trace.chosen() {
+          Some((backend, decision)) => println!("{} backend chose settings for {} (confidence {:.2}): {}",
+            backend, program, decision.confidence, decision.explanation),
+          None => println!("No decision backend reached confidence {:.2} for {}, running it unchanged", configs.decision.min_confidence, program)
+        }
+        config
+      } else {
+        configs.aliases.get(alias).map(|(config, _)| config.clone()).ok_or_else(|| format!("Alias {} not found", alias))?
+      };
+      let mut command = build_command(&run, &path);
+      let cgroup_root = configs.cgroup.root();
+      let policies = supervise::policies(&config);
+      let job_cgroup = if config.cgroup.is_empty() {None} else if policies.is_empty() {
+        Some(cgroup::Cgroup::create(&cgroup_root, &configs.cgroup.parent(), &format!("alias-{}", alias), &config.cgroup)?)
       } else {
-        let local_configs = load_local_config(local_config_file)?;
-        local_configs.get(&alias).cloned().ok_or_else(|| format!("Alias {} not found", alias))?
+        let parent = format!("{}/jobs", configs.cgroup.parent());
+        Some(cgroup::Cgroup::create(&cgroup_root, &parent, &format!("{}-{}", alias, getpid()), &config.cgroup)?)
       };
-      if !Path::new(program).exists() {return Err(format!("Program {} not found", program).into())}
+      let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC)?;
+      let terminal = owns_terminal(getpgrp());
+      install_signal_forwarding()?;
       unsafe {
         match fork()? {
           ForkResult::Parent { child } => {
-            apply_runtime_settings(child, &config)?;
+            CHILD_PGID.store(child.as_raw(), Ordering::SeqCst);
+            let _ = setpgid(child, child);
+            drop(write_end);
+            let mut child_error = String::new();
+            File::from(read_end).read_to_string(&mut child_error)?;
+            if !child_error.is_empty() {
+              waitpid(child, None)?;
+              if terminal {give_terminal(getpgrp())?}
+              return Err(child_error.into())
+            }
+            if let Err(e) = verify_job(child, job_cgroup.as_ref(), &cgroup_root) {eprintln!("{}, leaving the job running", e)}
             println!("Started {} with alias {}", program, alias);
-            waitpid(child, None)?;
+            let code = if policies.is_empty() {wait_for_child(child)} else {
+              let code = supervise::supervise(child, &config, job_cgroup.as_ref(), policies);
+              if let Some(job_cgroup) = &job_cgroup {job_cgroup.remove()}
+              code
+            };
+            if terminal {give_terminal(getpgrp())?}
+            std::process::exit(code?);
           }
           ForkResult::Child => {
-            setpgid(0, 0)?;
-            Command::new(program).stdin(Stdio::null()).stdout(Stdio::inherit()).stderr(Stdio::inherit()).exec();
-            std::process::exit(1);
+            drop(read_end);
+            let error = match setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(|e| format!("Setpgid error: {}", e))
+              .and_then(|_| if terminal {give_terminal(getpid())} else {Ok(())})
+              .and_then(|_| job_cgroup.as_ref().map_or(Ok(()), |c| c.add_process(getpid())))
+              .and_then(|_| apply_runtime_settings(getpid(), schedule::current(&config)))
+              .and_then(|_| verify_applied_settings(getpid(), schedule::current(&config))) {
+              Ok(()) => {
+                format!("Exec error: {}", command.exec())
+              }
+              Err(e) => e
+            };
+            let _ = File::from(write_end).write_all(error.as_bytes());
+            std::process::exit(127);
+          }
+        }
+      }
+    }
+    "explain" => {
+      let mut explain_args = args[2..].to_vec();
+      if explain_args.first().is_none_or(|arg| arg == "--") {explain_args.insert(0, "0".to_string())}
+      let run = match parse_run_args(&explain_args) {
+        Ok(run) => run,
+        Err(e) => {
+          eprintln!("{}", e);
+          eprintln!("Usage: polite explain [alias] -- <program> [args...]");
+          std::process::exit(1);
+        }
+      };
+      println!("Config files:");
+      for (path, required) in config_search_path(config_override.as_deref()) {
+        let state = if path.exists() {"loaded"} else if required {"missing"} else {"not found"};
+        println!("  {}: {}", path.display(), state);
+      }
+      let configs = load_configs(config_override.as_deref())?;
+      let path = find_program(&run)?;
+      println!("Program: {} ({})", run.argv().join(" "), path.display());
+      let config = if run.alias == "0" {
+        let (resolution, config) = resolve_alias(&configs, &path, &run);
+        match &resolution.ruleset {
+          Ok(ruleset) => {
+            println!("Online ruleset: {} with {} aliases and {} rules, fetched {}s ago (ttl {}s)", ruleset.source,
+              ruleset.configs.len(), ruleset.rules.len(), ruleset.age(), configs.online.ttl);
+            if let Some(e) = &ruleset.stale {println!("  stale, refresh failed: {}", e)}
           }
+          Err(_) if configs.online.sources.is_empty() => println!("Online ruleset: no sources configured"),
+          Err(e) => println!("Online ruleset: unavailable: {}", e)
         }
+        println!("Decision backends (min confidence {:.2}):", configs.decision.min_confidence);
+        let trace = &resolution.trace;
+        for outcome in &trace.outcomes {
+          match &outcome.result {
+            Ok(Some(decision)) => println!("  {}: confidence {:.2}, {}: {}", outcome.backend, decision.confidence,
+              decision.explanation, describe_config(&decision.config)),
+            Ok(None) => println!("  {}: nothing matched", outcome.backend),
+            Err(e) => println!("  {}: error: {}", outcome.backend, e)
+          }
+        }
+        for backend in resolution.backends.iter().skip(trace.outcomes.len()) {println!("  {}: not consulted", backend)}
+        match trace.chosen() {
+          Some((backend, _)) => println!("Chosen: {}", backend),
+          None => println!("Chosen: nothing reached confidence {:.2}, running unchanged", configs.decision.min_confidence)
+        }
+        config
+      } else {
+        let (config, source) = configs.aliases.get(&run.alias).ok_or_else(|| format!("Alias {} not found", run.alias))?;
+        println!("Alias {} defined in {}", run.alias, source);
+        config.clone()
+      };
+      println!("Final settings: {}", describe_config(&config));
+    }
+    "apply" => {
+      let apply = match parse_apply_args(&args[2..]) {
+        Ok(apply) => apply,
+        Err(e) => {
+          eprintln!("{}", e);
+          eprintln!("Usage: polite apply <alias> [-t|--threads] <pid...|--name pattern|--pgid pgid|--tree pid>");
+          std::process::exit(1);
+        }
+      };
+      let configs = load_configs(config_override.as_deref())?;
+      let (config, _) = configs.aliases.get(&apply.alias).ok_or_else(|| format!("Alias {} not found", apply.alias))?;
+      let mut config = config.clone();
+      if config.numa.take().is_some() {eprintln!("NUMA policy can only be set at launch, skipping it")}
+      let job_cgroup = if config.cgroup.is_empty() {None} else {
+        Some(cgroup::Cgroup::create(&configs.cgroup.root(), &configs.cgroup.parent(), &format!("alias-{}", apply.alias), &config.cgroup)?)
+      };
+      let mut failed = 0;
+      for pid in &apply.targets {
+        let comm = procfs::comm(*pid).unwrap_or_default();
+        let mut results = vec![(format!("PID {} ({})", pid, comm), apply_to_pid(*pid, &config, job_cgroup.as_ref()))];
+        if apply.threads {
+          for tid in procfs::threads(*pid).into_iter().filter(|tid| tid != pid) {
+            results.push((format!("  TID {} of PID {}", tid, pid), apply_to_pid(tid, &config, None)));
+          }
+        }
+        for (target, result) in results {
+          match result {
+            Ok(()) => println!("{}: applied alias {}", target, apply.alias),
+            Err(e) => {println!("{}: failed: {}", target, e); failed += 1}
+          }
+        }
+      }
+      if failed > 0 {
+        eprintln!("Failed to apply alias {} to {} target(s)", apply.alias, failed);
+        std::process::exit(1);
       }
     }
+    "daemon" => {
+      if args.len() != 2 {eprintln!("Usage: polite daemon"); std::process::exit(1);}
+      let configs = load_configs(config_override.as_deref())?;
+      daemon::run(configs, || load_configs(config_override.as_deref()))?;
+    }
     "status" => {
       if args.len() != 3 {eprintln!("Usage: polite status <pid>"); std::process::exit(1);}
       let pid: Pid = Pid::from_raw(args[2].parse()?);
-      println!("{}", get_applied_settings(pid)?);
+      println!("PID {}: {}", pid, describe_config(&get_applied_settings(pid)?));
+      let configs = load_configs(config_override.as_deref())?;
+      if let Ok(job_cgroup) = cgroup::Cgroup::for_pid(&configs.cgroup.root(), pid) {
+        let usage: Vec<String> = job_cgroup.usage().iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        if !usage.is_empty() {println!("Cgroup {}: {}", job_cgroup.name(), usage.join(", "))}
+      }
+      if let Some(alias) = procfs::environ(pid, "POLITE_ALIAS").filter(|alias| alias != "0") {
+        match configs.aliases.get(&alias) {
+          Some((config, _)) if !config.schedule.is_empty() => match schedule::active(config) {
+            Some(window) => println!("Alias {}: schedule window {} active", alias, window),
+            None => println!("Alias {}: no schedule window active, using the alias settings", alias)
+          },
+          Some(_) => println!("Alias {}", alias),
+          None => println!("Alias {}: no longer defined", alias)
+        }
+      }
+    }
+    "check" => {
+      let files: Vec<String> = if args.len() > 2 {args[2..].to_vec()} else {
+        config_search_path(config_override.as_deref()).into_iter()
+          .filter(|(path, required)| *required || path.exists()).map(|(path, _)| path.display().to_string()).collect()
+      };
+      if files.is_empty() {eprintln!("No config files found"); std::process::exit(1);}
+      let diagnostics: Vec<check::Diagnostic> = files.iter().flat_map(|file| check::check_file(file)).collect();
+      for diagnostic in &diagnostics {println!("{}", diagnostic)}
+      let errors = diagnostics.iter().filter(|d| d.severity == check::Severity::Error).count();
+      println!("Checked {} file(s): {} error(s), {} warning(s)", files.len(), errors, diagnostics.len() - errors);
+      if errors > 0 {std::process::exit(1);}
+    }
+    "update" => {
+      let configs = load_configs(config_override.as_deref())?;
+      let (ruleset, path) = online::update(&configs.online)?;
+      println!("Fetched {} online rules from {}, cached at {}", ruleset.configs.len(), ruleset.source, path.display());
     }
     "list" => {
-      let local_configs = load_local_config("polite.conf")?;
-      for (alias, config) in local_configs {
-        println!("Alias {}: niceness={}, oom_score_adj={}", alias, config.niceness, config.oom_score_adj);
+      let loaded = load_configs(config_override.as_deref())?;
+      let mut configs: Vec<_> = loaded.aliases.into_iter().collect();
+      configs.sort_by(|(a, _), (b, _)| a.cmp(b));
+      for (alias, (config, source)) in configs {
+        println!("Alias {}: {} ({})", alias, describe_config(&config), source);
+      }
+      for rule in &loaded.rules {
+        println!("Rule {}: alias {}, priority {} ({})", rule.name, rule.alias, rule.priority, rule.source);
+      }
+    }
+    "config" => {
+      if args.get(2).map(String::as_str) != Some("convert") || args.len() > 5 {
+        eprintln!("Usage: polite config convert [input] [output]");
+        std::process::exit(1);
+      }
+      let input = args.get(3).map_or("polite.conf", String::as_str);
+      let converted = config::convert_legacy_config(input)?;
+      match args.get(4) {
+        Some(output) => {
+          if Path::new(output).exists() {return Err(format!("{} already exists", output).into())}
+          std::fs::write(output, converted)?;
+          println!("Converted {} to {}", input, output);
+        }
+        None => print!("{}", converted)
       }
     }
     _ => eprintln!("Unknown command: {}", command)
   }
   Ok(())
   }
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use super::*;
+
+  pub fn temp_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("polite-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    root
+  }
+
+  fn run_args(program: &str, cwd: Option<String>, path: Option<&str>) -> RunArgs {
+    RunArgs {alias: "low".to_string(), program: program.to_string(), args: Vec::new(),
+      env: path.map(|path| vec![("PATH".to_string(), Some(path.to_string()))]).unwrap_or_default(), clear_env: false, cwd}
+  }
+
+  #[test]
+  fn resolves_programs_to_absolute_paths() {
+    let root = temp_root("find-program");
+    std::fs::create_dir(root.join("sub")).unwrap();
+    let script = root.join("sub/hello.sh");
+    std::fs::write(&script, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let relative = format!("{}{}", "../".repeat(std::env::current_dir().unwrap().components().count()), root.display());
+    let found = find_program(&run_args("./hello.sh", Some(format!("{}/sub", relative)), None)).unwrap();
+    assert!(found.is_absolute() && found.canonicalize().unwrap() == script.canonicalize().unwrap());
+    let found = find_program(&run_args("hello.sh", None, Some(&format!("/nonexistent:{}/sub", relative)))).unwrap();
+    assert!(found.is_absolute() && found.canonicalize().unwrap() == script.canonicalize().unwrap());
+    assert_eq!(find_program(&run_args(&script.display().to_string(), Some("/".to_string()), None)), Ok(script.clone()));
+    assert!(find_program(&run_args("./hello.sh", None, None)).is_err());
+    assert!(find_program(&run_args("hello.sh", None, Some("/nonexistent"))).unwrap_err().ends_with("not found in PATH"));
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn rejects_unknown_settings() {
+    let mut config = PoliteConfig::default();
+    assert_eq!(apply_setting(&mut config, "bogus", "3"), Err("Unknown setting bogus".to_string()));
+    assert_eq!(apply_setting(&mut config, "cpu.bogus", "3"), Err("Unknown cgroup setting cpu.bogus".to_string()));
+    apply_setting(&mut config, "cpu.weight", "30").unwrap();
+    apply_setting(&mut config, "nofile", "64").unwrap();
+    assert_eq!(config.cgroup.cpu_weight, Some(30));
+  }
+
+  #[test]
+  fn parses_cpu_lists() {
+    assert_eq!(parse_cpu_list("0"), Ok(vec![0]));
+    assert_eq!(parse_cpu_list("0-3"), Ok(vec![0, 1, 2, 3]));
+    assert_eq!(parse_cpu_list(" 4, 0-1 ,1,6-7"), Ok(vec![0, 1, 4, 6, 7]));
+    assert!(parse_cpu_list("").is_err());
+    assert!(parse_cpu_list(",").is_err());
+    assert!(parse_cpu_list("3-1").is_err());
+    assert!(parse_cpu_list("0-x").is_err());
+    assert!(parse_cpu_list("-1").is_err());
+  }
+
+  #[test]
+  fn formats_cpu_lists() {
+    assert_eq!(format_cpu_list(&[0]), "0");
+    assert_eq!(format_cpu_list(&[0, 1, 2, 4, 6, 7]), "0-2,4,6-7");
+    assert_eq!(format_cpu_list(&parse_cpu_list("8-11,2").unwrap()), "2,8-11");
+  }
+
+  #[test]
+  fn parses_numa_policies() {
+    let numa: NumaPolicy = "interleave:0-1".parse().unwrap();
+    assert_eq!(numa, NumaPolicy {mode: MPOL_INTERLEAVE, nodes: vec![0, 1]});
+    assert_eq!(numa.to_string(), "interleave:0-1");
+    assert!("spread:0".parse::<NumaPolicy>().is_err());
+    assert!("bind".parse::<NumaPolicy>().is_err());
+  }
+}
@@ -0,0 +1,267 @@
+use std::fs::{read_dir, read_to_string, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use glob::Pattern;
+use goblin::container::Ctx;
+use goblin::elf::dynamic::Dynamic;
+use goblin::elf::header::header64::SIZEOF_EHDR;
+use goblin::elf::program_header::{ProgramHeader, PT_DYNAMIC};
+use goblin::elf::Elf;
+use goblin::strtab::Strtab;
+use crate::rules::Process;
+use crate::{IoClass, PoliteConfig, SchedPolicy};
+
+const ELF_READ_LIMIT: u64 = 1 << 20;
+
+const GUI_LIBRARIES: [&str; 12] = ["libgtk-*", "libgdk-*", "libQt*Gui.so*", "libQt*Widgets.so*", "libKF*", "libX11.so*",
+  "libxcb.so*", "libwayland-client.so*", "libSDL2*", "libglfw.so*", "libwx_*", "libfltk*"];
+
+const KNOWN_PROGRAMS: [(Class, &[&str]); 4] = [
+  (Class::Batch, &["cc", "c++", "gcc", "gcc-*", "g++", "g++-*", "clang", "clang-*", "clang++", "rustc", "cargo", "make", "ninja",
+    "cmake", "ld", "ld.*", "javac", "go", "tsc", "ffmpeg", "x264", "x265", "HandBrakeCLI", "lame", "flac", "opusenc", "oggenc",
+    "gzip", "bzip2", "xz", "zstd", "7z", "tar", "rsync", "convert", "magick", "blender"]),
+  (Class::Background, &["boinc", "boinc_client", "FAHClient", "updatedb", "plocate-build", "mandb", "baloo_file",
+    "baloo_file_extractor", "tracker-miner-fs*", "tracker-extract*", "recollindex", "restic", "borg", "duplicity", "fstrim"]),
+  (Class::Server, &["nginx", "httpd", "apache2", "caddy", "postgres", "mysqld", "mariadbd", "redis-server", "memcached",
+    "sshd", "dockerd", "containerd", "gunicorn", "uvicorn"]),
+  (Class::Interactive, &["vim", "nvim", "emacs", "nano", "less", "top", "htop", "ssh", "tmux", "screen"])
+];
+
+const INTERACTIVE_CATEGORIES: [&str; 9] = ["AudioVideo", "Audio", "Video", "Game", "Graphics", "Office", "Network", "TerminalEmulator", "Utility"];
+
+const SERVER_ARGS: [&str; 5] = ["--daemon", "--listen", "--port", "--bind", "serve"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+  Interactive,
+  Batch,
+  Background,
+  Server
+}
+
+impl std::fmt::Display for Class {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str(match self {
+      Class::Interactive => "interactive",
+      Class::Batch => "batch",
+      Class::Background => "background",
+      Class::Server => "server"
+    })
+  }
+}
+
+impl Class {
+  pub fn default_config(self) -> PoliteConfig {
+    match self {
+      Class::Interactive => PoliteConfig::default(),
+      Class::Batch => PoliteConfig {niceness: Some(10), oom_score_adj: Some(300), io_class: IoClass::BestEffort, io_level: 7,
+        sched_policy: SchedPolicy::Batch, ..PoliteConfig::default()},
+      Class::Background => PoliteConfig {niceness: Some(19), oom_score_adj: Some(500), io_class: IoClass::Idle, io_level: 7,
+        sched_policy: SchedPolicy::Idle, ..PoliteConfig::default()},
+      Class::Server => PoliteConfig {io_class: IoClass::BestEffort, io_level: 4, ..PoliteConfig::default()}
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Classification {
+  pub class: Class,
+  pub confidence: f64,
+  pub reasons: Vec<String>
+}
+
+fn matches_any(patterns: &[&str], name: &str) -> bool {
+  patterns.iter().any(|pattern| Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(name)))
+}
+
+fn read_at(file: &mut File, offset: u64, length: u64) -> Option<Vec<u8>> {
+  if length > ELF_READ_LIMIT {return None}
+  let mut bytes = vec![0; length as usize];
+  file.seek(SeekFrom::Start(offset)).ok()?;
+  file.read_exact(&mut bytes).ok()?;
+  Some(bytes)
+}
+
+fn needed_libraries(path: &Path) -> Option<Vec<String>> {
+  let mut file = File::open(path).ok()?;
+  let header = Elf::parse_header(&read_at(&mut file, 0, SIZEOF_EHDR as u64)?).ok()?;
+  let ctx = Ctx::new(header.container().ok()?, header.endianness().ok()?);
+  let table = read_at(&mut file, header.e_phoff, header.e_phnum as u64 * header.e_phentsize as u64)?;
+  let mut phdrs = ProgramHeader::parse(&table, 0, header.e_phnum as usize, ctx).ok()?;
+  let dynamic = phdrs.iter_mut().find(|phdr| phdr.p_type == PT_DYNAMIC)?;
+  let bytes = read_at(&mut file, dynamic.p_offset, dynamic.p_filesz)?;
+  dynamic.p_offset = 0;
+  let dynamic = Dynamic::parse(&bytes, &phdrs, ctx).ok()??;
+  let strings = read_at(&mut file, dynamic.info.strtab as u64, dynamic.info.strsz as u64)?;
+  let strtab = Strtab::parse(&strings, 0, strings.len(), 0).ok()?;
+  Some(dynamic.get_libraries(&strtab).into_iter().map(str::to_string).collect())
+}
+
+fn linked_libraries(path: &Path) -> Vec<String> {
+  needed_libraries(path).unwrap_or_default()
+}
+
+fn application_dirs() -> Vec<PathBuf> {
+  let mut dirs = Vec::new();
+  match std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+    Some(dir) => dirs.push(PathBuf::from(dir)),
+    None => if let Some(home) = std::env::var_os("HOME") {dirs.push(PathBuf::from(home).join(".local/share"))}
+  }
+  let data_dirs = std::env::var("XDG_DATA_DIRS").ok().filter(|dirs| !dirs.is_empty()).unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+  dirs.extend(data_dirs.split(':').filter(|dir| !dir.is_empty()).map(PathBuf::from));
+  dirs.into_iter().map(|dir| dir.join("applications")).collect()
+}
+
+fn desktop_categories(executable: &str) -> Option<(PathBuf, Vec<String>)> {
+  for dir in application_dirs() {
+    let Ok(entries) = read_dir(&dir) else {continue};
+    for entry in entries.flatten().filter(|entry| entry.path().extension().is_some_and(|ext| ext == "desktop")) {
+      let Ok(text) = read_to_string(entry.path()) else {continue};
+      let (mut runs, mut categories, mut in_entry) = (false, Vec::new(), false);
+      for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {in_entry = line == "[Desktop Entry]"; continue}
+        if !in_entry {continue}
+        if let Some(command) = line.strip_prefix("Exec=").or_else(|| line.strip_prefix("TryExec=")) {
+          let program = command.split_whitespace().find(|word| *word != "env" && !word.contains('=')).unwrap_or("");
+          runs |= Path::new(program.trim_matches('"')).file_name().is_some_and(|name| name == executable);
+        }
+        if let Some(list) = line.strip_prefix("Categories=") {categories = list.split(';').filter(|c| !c.is_empty()).map(str::to_string).collect()}
+      }
+      if runs {return Some((entry.path(), categories))}
+    }
+  }
+  None
+}
+
+pub fn classify(process: &Process) -> Classification {
+  let executable = process.executable();
+  let mut scores = [(Class::Interactive, 0.0), (Class::Batch, 0.0), (Class::Background, 0.0), (Class::Server, 0.0)];
+  let mut reasons = Vec::new();
+  let mut score = |class: Class, weight: f64, reason: String| {
+    if let Some((_, total)) = scores.iter_mut().find(|(c, _)| *c == class) {*total += weight}
+    reasons.push(format!("{} (+{:.1} {})", reason, weight, class));
+  };
+  if let Some((class, _)) = KNOWN_PROGRAMS.iter().find(|(_, names)| matches_any(names, &executable)) {
+    score(*class, 0.6, format!("{} is a known {} program", executable, class));
+  }
+  let gui: Vec<String> = linked_libraries(&process.path).into_iter().filter(|library| matches_any(&GUI_LIBRARIES, library)).collect();
+  if !gui.is_empty() {score(Class::Interactive, 0.4, format!("links GUI libraries {}", gui.join(", ")))}
+  if let Some((file, categories)) = desktop_categories(&executable) {
+    let matched: Vec<&String> = categories.iter().filter(|category| INTERACTIVE_CATEGORIES.contains(&category.as_str())).collect();
+    let weight = if matched.is_empty() {0.3} else {0.5};
+    score(Class::Interactive, weight, format!("has a desktop entry {} with categories {}", file.display(), categories.join(";")));
+  }
+  let args = process.argv.get(1..).unwrap_or_default();
+  if args.iter().any(|arg| SERVER_ARGS.iter().any(|server| arg == server || arg.starts_with(&format!("{}=", server)))) {
+    score(Class::Server, 0.3, "arguments ask to listen or daemonize".to_string());
+  }
+  if args.iter().any(|arg| arg == "-o" || arg == "--output" || arg.starts_with("--output=")) {
+    score(Class::Batch, 0.2, "arguments name an output file".to_string());
+  }
+  if args.iter().any(|arg| arg.starts_with("-j") || arg.starts_with("--jobs")) {
+    score(Class::Batch, 0.2, "arguments request parallel jobs".to_string());
+  }
+  let files = args.iter().filter(|arg| !arg.starts_with('-') && Path::new(arg).is_file()).count();
+  if files >= 3 {score(Class::Batch, 0.2, format!("arguments name {} input files", files))}
+  let total: f64 = scores.iter().map(|(_, score)| score).sum();
+  let (class, best) = scores.iter().fold((Class::Interactive, 0.0), |best, (class, score)| if *score > best.1 {(*class, *score)} else {best});
+  if total == 0.0 {
+    return Classification {class: Class::Interactive, confidence: 0.1, reasons: vec![format!("no evidence about {}, assuming interactive", executable)]}
+  }
+  Classification {class, confidence: (best / total * best.min(1.0)).clamp(0.0, 1.0), reasons}
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  static DATA_DIRS: Mutex<()> = Mutex::new(());
+
+  fn classified(path: &str, args: &[&str], data_dir: &Path) -> Classification {
+    let _guard = DATA_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("XDG_DATA_HOME", data_dir.join("home"));
+    std::env::set_var("XDG_DATA_DIRS", data_dir);
+    let name = Path::new(path).file_name().unwrap().to_string_lossy().into_owned();
+    let argv = std::iter::once(name).chain(args.iter().map(|arg| arg.to_string())).collect();
+    classify(&Process {path: PathBuf::from(path), argv, parent: "bash".to_string(), uid: 1000, user: "alice".to_string()})
+  }
+
+  #[test]
+  fn classifies_known_programs() {
+    let root = crate::tests::temp_root("heuristics-known");
+    for (path, class) in [("/opt/bin/ffmpeg", Class::Batch), ("/opt/bin/gcc-13", Class::Batch), ("/opt/bin/boinc", Class::Background),
+      ("/opt/bin/nginx", Class::Server), ("/opt/bin/nvim", Class::Interactive)] {
+      let classification = classified(path, &[], &root);
+      assert_eq!(classification.class, class, "{}", path);
+      assert!((classification.confidence - 0.6).abs() < 1e-9);
+      assert!(classification.reasons[0].contains(&format!("is a known {} program", class)));
+    }
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn classifies_argument_shapes() {
+    let root = crate::tests::temp_root("heuristics-args");
+    for name in ["a.wav", "b.wav", "c.wav"] {std::fs::write(root.join(name), "").unwrap()}
+    let file = |name: &str| root.join(name).display().to_string();
+    let server = classified("/opt/bin/mytool", &["--port=8080"], &root);
+    assert_eq!((server.class, server.confidence), (Class::Server, 0.3));
+    assert_eq!(classified("/opt/bin/mytool", &["serve", "--bind", "::"], &root).class, Class::Server);
+    let batch = classified("/opt/bin/mytool", &["-j8", "-o", "out.bin"], &root);
+    assert_eq!((batch.class, batch.reasons.len()), (Class::Batch, 2));
+    assert!((batch.confidence - 0.4).abs() < 1e-9);
+    let inputs = classified("/opt/bin/mytool", &[&file("a.wav"), &file("b.wav"), &file("c.wav")], &root);
+    assert_eq!(inputs.class, Class::Batch);
+    assert!(inputs.reasons[0].contains("3 input files"));
+    assert_eq!(classified("/opt/bin/mytool", &[&file("a.wav"), &file("b.wav")], &root).confidence, 0.1);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn classifies_desktop_entries() {
+    let root = crate::tests::temp_root("heuristics-desktop");
+    std::fs::create_dir_all(root.join("applications")).unwrap();
+    std::fs::write(root.join("applications/game.desktop"),
+      "[Desktop Entry]\nName=Game\nExec=env LANG=C \"/opt/game/mygame\" %U\nCategories=Game;ActionGame;\n\n[Desktop Action x]\nExec=other\n").unwrap();
+    std::fs::write(root.join("applications/ide.desktop"), "[Desktop Entry]\nExec=myide %F\nCategories=Development;IDE;\n").unwrap();
+    std::fs::write(root.join("applications/action.desktop"), "[Desktop Entry]\nExec=launcher\n[Desktop Action new]\nExec=other --new\n").unwrap();
+    let game = classified("/usr/games/mygame", &[], &root);
+    assert_eq!((game.class, game.confidence), (Class::Interactive, 0.5));
+    assert!(game.reasons[0].contains("categories Game;ActionGame"));
+    assert_eq!(classified("/usr/bin/myide", &[], &root).confidence, 0.3);
+    assert_eq!(classified("/usr/bin/other", &[], &root).confidence, 0.1);
+    let encoder = classified("/usr/bin/ffmpeg", &[], &root);
+    assert_eq!(encoder.class, Class::Batch);
+    std::fs::write(root.join("applications/ffmpeg.desktop"), "[Desktop Entry]\nExec=ffmpeg\nCategories=AudioVideo;\n").unwrap();
+    let encoder = classified("/usr/bin/ffmpeg", &[], &root);
+    assert_eq!((encoder.class, encoder.reasons.len()), (Class::Batch, 2));
+    assert!((encoder.confidence - 0.6 / 1.1 * 0.6).abs() < 1e-9);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn assumes_interactive_without_evidence() {
+    let root = crate::tests::temp_root("heuristics-none");
+    let classification = classified("/opt/bin/mytool", &["--verbose", "input"], &root);
+    assert_eq!((classification.class, classification.confidence), (Class::Interactive, 0.1));
+    assert_eq!(classification.reasons, ["no evidence about mytool, assuming interactive"]);
+    assert_eq!(Class::Interactive.default_config(), PoliteConfig::default());
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn reads_needed_libraries_from_the_dynamic_section() {
+    for path in [std::env::current_exe().unwrap(), PathBuf::from("/bin/sh")] {
+      let bytes = std::fs::read(&path).unwrap();
+      let expected: Vec<String> = Elf::parse(&bytes).unwrap().libraries.iter().map(|library| library.to_string()).collect();
+      assert!(!expected.is_empty());
+      assert_eq!(linked_libraries(&path), expected);
+    }
+    let root = crate::tests::temp_root("heuristics-elf");
+    std::fs::write(root.join("script"), "#!/bin/sh\n").unwrap();
+    assert!(linked_libraries(&root.join("script")).is_empty());
+    assert!(linked_libraries(&root.join("missing")).is_empty());
+    std::fs::remove_dir_all(root).unwrap();
+  }
+}
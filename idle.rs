@@ -0,0 +1,250 @@
+use std::fs::{metadata, read_dir, read_to_string};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use crate::supervise::Policy;
+use crate::PoliteConfig;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RESUME_AFTER: f64 = 7.0 * 24.0 * 60.0;
+const INPUT_INTERRUPTS: [&str; 7] = ["i8042", "hid", "usb", "keyboard", "mouse", "touchpad", "trackpad"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdlePolicy {
+  pub resume_after: f64,
+  pub source: String
+}
+
+impl Default for IdlePolicy {
+  fn default() -> Self {
+    IdlePolicy {resume_after: 5.0, source: "input".to_string()}
+  }
+}
+
+impl IdlePolicy {
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    match key {
+      "resume_after" => self.resume_after = value.parse().ok().filter(|m: &f64| m.is_finite() && (0.0..=MAX_RESUME_AFTER).contains(m))
+        .ok_or_else(|| format!("idle.resume_after must be a number of minutes between 0 and {}", MAX_RESUME_AFTER))?,
+      "source" => {
+        if !matches!(value, "input" | "interrupts") && value.strip_prefix("file:").is_none_or(str::is_empty) {
+          return Err(format!("idle.source must be input, interrupts or file:PATH, not {}", value))
+        }
+        self.source = value.to_string();
+      }
+      _ => return Err(format!("Unknown idle setting {}", key))
+    }
+    Ok(())
+  }
+
+  pub fn settings(&self) -> Vec<(&'static str, String)> {
+    vec![("resume_after", self.resume_after.to_string()), ("source", self.source.clone())]
+  }
+}
+
+impl std::fmt::Display for IdlePolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "pause while {} shows activity, resume after {} idle minute(s)", self.source, self.resume_after)
+  }
+}
+
+pub trait ActivitySource {
+  fn last_activity(&mut self) -> Result<SystemTime, String>;
+}
+
+pub struct InputDevices {
+  root: PathBuf
+}
+
+impl ActivitySource for InputDevices {
+  fn last_activity(&mut self) -> Result<SystemTime, String> {
+    let entries = read_dir(&self.root).map_err(|e| format!("Idle error: {}: {}", self.root.display(), e))?;
+    entries.flatten().filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+      .filter_map(|entry| entry.metadata().ok())
+      .flat_map(|metadata| [metadata.accessed().ok(), metadata.modified().ok()]).flatten().max()
+      .ok_or_else(|| format!("Idle error: no readable event devices in {}", self.root.display()))
+  }
+}
+
+pub struct Interrupts {
+  path: PathBuf,
+  count: Option<u64>,
+  changed: SystemTime
+}
+
+impl ActivitySource for Interrupts {
+  fn last_activity(&mut self) -> Result<SystemTime, String> {
+    let text = read_to_string(&self.path).map_err(|e| format!("Idle error: {}: {}", self.path.display(), e))?;
+    let count = text.lines().filter(|line| {
+      let line = line.to_lowercase();
+      INPUT_INTERRUPTS.iter().any(|name| line.contains(name))
+    }).flat_map(|line| line.split_whitespace().skip(1).map_while(|field| field.parse::<u64>().ok())).sum();
+    if self.count.is_some_and(|previous| previous != count) {self.changed = SystemTime::now()}
+    self.count = Some(count);
+    Ok(self.changed)
+  }
+}
+
+pub struct ActivityFile {
+  path: PathBuf
+}
+
+impl ActivitySource for ActivityFile {
+  fn last_activity(&mut self) -> Result<SystemTime, String> {
+    metadata(&self.path).and_then(|metadata| metadata.modified()).map_err(|e| format!("Idle error: {}: {}", self.path.display(), e))
+  }
+}
+
+pub fn source(spec: &str) -> Box<dyn ActivitySource> {
+  match spec.strip_prefix("file:") {
+    Some(path) => Box::new(ActivityFile {path: PathBuf::from(path)}),
+    None if spec == "interrupts" => Box::new(Interrupts {path: PathBuf::from("/proc/interrupts"), count: None, changed: SystemTime::UNIX_EPOCH}),
+    None => Box::new(InputDevices {root: PathBuf::from(std::env::var("POLITE_INPUT_ROOT").unwrap_or_else(|_| "/dev/input".to_string()))})
+  }
+}
+
+pub struct IdleWatcher {
+  resume_after: Duration,
+  source: Box<dyn ActivitySource>,
+  active: bool,
+  checked: Option<Instant>,
+  failing: bool
+}
+
+impl IdleWatcher {
+  pub fn new(policy: &IdlePolicy, source: Box<dyn ActivitySource>) -> IdleWatcher {
+    IdleWatcher {resume_after: Duration::try_from_secs_f64(policy.resume_after * 60.0).unwrap_or(Duration::MAX), source, active: false, checked: None, failing: false}
+  }
+
+  fn check(&mut self) {
+    let last_activity = match self.source.last_activity() {
+      Ok(last_activity) => {self.failing = false; last_activity}
+      Err(e) => {
+        if !self.failing {eprintln!("{}, not pausing for user activity", e)}
+        self.failing = true;
+        self.active = false;
+        return
+      }
+    };
+    let idle = SystemTime::now().duration_since(last_activity).unwrap_or_default();
+    let active = idle < self.resume_after;
+    if active && !self.active {eprintln!("User is active, pausing the job")}
+    if !active && self.active {eprintln!("User idle for {}s, resuming the job", idle.as_secs())}
+    self.active = active;
+  }
+}
+
+impl Policy for IdleWatcher {
+  fn adjust(&mut self, _config: &mut PoliteConfig) -> bool {
+    if self.checked.is_none_or(|checked| checked.elapsed() >= CHECK_INTERVAL) {
+      self.checked = Some(Instant::now());
+      self.check();
+    }
+    self.active
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::RefCell;
+  use std::fs::{File, FileTimes};
+  use std::path::Path;
+  use std::rc::Rc;
+
+  struct Fake(Rc<RefCell<Result<SystemTime, String>>>);
+
+  impl ActivitySource for Fake {
+    fn last_activity(&mut self) -> Result<SystemTime, String> {
+      self.0.borrow().clone()
+    }
+  }
+
+  fn ago(seconds: u64) -> SystemTime {
+    SystemTime::now() - Duration::from_secs(seconds)
+  }
+
+  fn touch(path: &Path, time: SystemTime) {
+    File::create(path).unwrap().set_times(FileTimes::new().set_accessed(time).set_modified(time)).unwrap();
+  }
+
+  #[test]
+  fn limits_resume_after() {
+    let mut policy = IdlePolicy::default();
+    for invalid in ["-1", "inf", "NaN", "1e300", "10081"] {assert!(policy.set("resume_after", invalid).is_err(), "{}", invalid)}
+    policy.set("resume_after", "10080").unwrap();
+    assert_eq!(IdleWatcher::new(&policy, source("input")).resume_after, Duration::from_secs(7 * 24 * 3600));
+    let huge = IdlePolicy {resume_after: f64::INFINITY, ..IdlePolicy::default()};
+    assert_eq!(IdleWatcher::new(&huge, source("input")).resume_after, Duration::MAX);
+  }
+
+  #[test]
+  fn pauses_while_active_and_resumes_when_idle() {
+    let activity = Rc::new(RefCell::new(Ok(ago(10))));
+    let mut watcher = IdleWatcher::new(&IdlePolicy {resume_after: 1.0, ..IdlePolicy::default()}, Box::new(Fake(activity.clone())));
+    assert!(watcher.adjust(&mut PoliteConfig::default()));
+    *activity.borrow_mut() = Ok(ago(90));
+    assert!(watcher.adjust(&mut PoliteConfig::default()));
+    watcher.check();
+    assert!(!watcher.active);
+    *activity.borrow_mut() = Ok(ago(0));
+    watcher.check();
+    assert!(watcher.active);
+  }
+
+  #[test]
+  fn runs_on_when_activity_is_unreadable() {
+    let activity = Rc::new(RefCell::new(Ok(ago(0))));
+    let mut watcher = IdleWatcher::new(&IdlePolicy::default(), Box::new(Fake(activity.clone())));
+    watcher.check();
+    assert!(watcher.active);
+    *activity.borrow_mut() = Err("Idle error: gone".to_string());
+    watcher.check();
+    assert!(!watcher.active && watcher.failing);
+    *activity.borrow_mut() = Ok(ago(0));
+    watcher.check();
+    assert!(watcher.active && !watcher.failing);
+  }
+
+  #[test]
+  fn reads_the_newest_event_device() {
+    let root = crate::tests::temp_root("idle-input");
+    let mut devices = InputDevices {root: root.clone()};
+    assert!(devices.last_activity().unwrap_err().contains("no readable event devices"));
+    touch(&root.join("event0"), ago(600));
+    touch(&root.join("event1"), ago(60));
+    touch(&root.join("mouse0"), ago(0));
+    let last = devices.last_activity().unwrap();
+    assert!(last <= ago(59) && last >= ago(61));
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(devices.last_activity().is_err());
+  }
+
+  #[test]
+  fn reads_the_activity_file() {
+    let root = crate::tests::temp_root("idle-file");
+    let path = root.join("activity");
+    let mut file = source(&format!("file:{}", path.display()));
+    assert!(file.last_activity().is_err());
+    touch(&path, ago(120));
+    let last = file.last_activity().unwrap();
+    assert!(last <= ago(119) && last >= ago(121));
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn counts_input_interrupts() {
+    let root = crate::tests::temp_root("idle-interrupts");
+    let path = root.join("interrupts");
+    let write = |keyboard: u64, timer: u64| std::fs::write(&path, format!(
+      "           CPU0       CPU1\n  0:  {}  0  IO-APIC   2-edge      timer\n  1:  {}  3  IO-APIC   1-edge      i8042\nNMI:  0  0  Non-maskable interrupts\n", timer, keyboard)).unwrap();
+    let mut interrupts = Interrupts {path: path.clone(), count: None, changed: SystemTime::UNIX_EPOCH};
+    write(5, 100);
+    assert_eq!(interrupts.last_activity(), Ok(SystemTime::UNIX_EPOCH));
+    assert_eq!(interrupts.count, Some(8));
+    write(5, 200);
+    assert_eq!(interrupts.last_activity(), Ok(SystemTime::UNIX_EPOCH));
+    write(6, 200);
+    assert!(interrupts.last_activity().unwrap() >= ago(1));
+    std::fs::remove_dir_all(root).unwrap();
+  }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use minisign_verify::{PublicKey, Signature};
+use toml::Table;
+use crate::config::{self, LoadedConfig};
+use crate::rules::Rule;
+use crate::{PoliteConfig, parse_config_line};
+
+const CACHE_PREFIX: &str = "# polite-";
+const RETRY_AFTER: u64 = 300;
+
+#[derive(Debug, Clone)]
+pub struct OnlineSettings {
+  pub ttl: u64,
+  pub timeout: u64,
+  pub sources: Vec<String>,
+  pub public_keys: Vec<String>,
+  pub allow_unsigned: bool
+}
+
+impl Default for OnlineSettings {
+  fn default() -> Self {
+    OnlineSettings {ttl: 3600, timeout: 10, sources: Vec::new(), public_keys: Vec::new(), allow_unsigned: false}
+  }
+}
+
+impl OnlineSettings {
+  pub fn update(&mut self, table: &Table) -> Result<(), String> {
+    for (key, value) in table {
+      match key.as_str() {
+        "ttl" => self.ttl = value.as_integer().and_then(|i| u64::try_from(i).ok()).ok_or("online.ttl must be a non-negative integer")?,
+        "timeout" => self.timeout = value.as_integer().and_then(|i| u64::try_from(i).ok()).ok_or("online.timeout must be a non-negative integer")?,
+        "sources" => {
+          let sources = value.as_array().ok_or("online.sources must be an array of URLs")?;
+          self.sources = sources.iter().map(|source| match source.as_str() {
+            Some(url) if ["file://", "http://", "https://"].iter().any(|scheme| url.starts_with(scheme)) => Ok(url.to_string()),
+            Some(url) => Err(format!("online.sources: unsupported URL {}, expected file://, http:// or https://", url)),
+            None => Err("online.sources must be an array of URLs".to_string())
+          }).collect::<Result<_, String>>()?;
+        }
+        "public_keys" => {
+          let keys = value.as_array().ok_or("online.public_keys must be an array of minisign public keys")?;
+          self.public_keys = keys.iter().map(|key| {
+            let key = key.as_str().ok_or("online.public_keys must be an array of minisign public keys")?.trim();
+            PublicKey::from_base64(key).map_err(|e| format!("online.public_keys: invalid key {}: {}", key, e))?;
+            Ok(key.to_string())
+          }).collect::<Result<_, String>>()?;
+        }
+        "allow_unsigned" => self.allow_unsigned = value.as_bool().ok_or("online.allow_unsigned must be a boolean")?,
+        _ => return Err(format!("Unknown key online.{}", key))
+      }
+    }
+    Ok(())
+  }
+
+  fn verify(&self, text: &str, signature: Option<&str>) -> Result<(), String> {
+    let signature = match signature {
+      Some(signature) if !self.public_keys.is_empty() => signature,
+      _ if self.allow_unsigned => return Ok(()),
+      None => return Err("Signature error: ruleset is not signed".to_string()),
+      Some(_) => return Err("Signature error: no online.public_keys configured to verify the ruleset".to_string())
+    };
+    let signature = Signature::decode(signature).map_err(|e| format!("Signature error: malformed signature: {}", e))?;
+    for key in &self.public_keys {
+      let key = PublicKey::from_base64(key).map_err(|e| format!("Signature error: {}", e))?;
+      if key.verify(text.as_bytes(), &signature, false).is_ok() {return Ok(())}
+    }
+    Err("Signature error: ruleset signature does not match any key in online.public_keys".to_string())
+  }
+}
+
+pub struct Ruleset {
+  pub configs: HashMap<String, PoliteConfig>,
+  pub rules: Vec<Rule>,
+  pub source: String,
+  pub fetched: u64,
+  pub stale: Option<String>
+}
+
+impl Ruleset {
+  pub fn age(&self) -> u64 {
+    now().saturating_sub(self.fetched)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CacheEntry {
+  fetched: u64,
+  source: String,
+  etag: Option<String>,
+  last_modified: Option<String>,
+  signature: Option<String>,
+  failed: Option<(u64, String)>,
+  text: String
+}
+
+enum Fetched {
+  Modified {text: String, signature: Option<String>, etag: Option<String>, last_modified: Option<String>},
+  NotModified
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn cache_file() -> PathBuf {
+  let dir = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+    Some(dir) => PathBuf::from(dir),
+    None => std::env::var_os("HOME").map_or_else(std::env::temp_dir, |home| PathBuf::from(home).join(".cache"))
+  };
+  dir.join("polite").join("online.conf")
+}
+
+fn signature_file(path: &std::path::Path) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(".minisig");
+  PathBuf::from(name)
+}
+
+fn parse_ruleset(text: &str, source: &str) -> Result<(HashMap<String, PoliteConfig>, Vec<Rule>), String> {
+  if source.ends_with(".toml") {
+    let mut loaded = LoadedConfig::default();
+    config::parse_toml_config(text, source, &mut loaded)?;
+    let configs: HashMap<String, PoliteConfig> = loaded.aliases.into_iter().map(|(alias, (config, _))| (alias, config)).collect();
+    if configs.is_empty() && loaded.rules.is_empty() {return Err("No valid online configs".to_string())}
+    return Ok((configs, loaded.rules))
+  }
+  let mut configs = HashMap::new();
+  for line in text.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#')) {
+    if let Ok((alias, config)) = parse_config_line(line) {
+      configs.insert(alias, config);
+    }
+  }
+  if configs.is_empty() {Err("No valid online configs".to_string())} else {Ok((configs, Vec::new()))}
+}
+
+fn fetch_source(source: &str, cached: Option<&CacheEntry>, timeout: u64) -> Result<Fetched, String> {
+  if let Some(path) = source.strip_prefix("file://") {
+    let text = read_to_string(path).map_err(|e| format!("Fetch error: {}", e))?;
+    let signature = read_to_string(signature_file(path.as_ref())).ok();
+    return Ok(Fetched::Modified {text, signature, etag: None, last_modified: None})
+  }
+  let client = Client::builder().timeout(Duration::from_secs(timeout)).build().map_err(|e| format!("Fetch error: {}", e))?;
+  let mut request = client.get(source);
+  if let Some(cached) = cached.filter(|cached| cached.source == source) {
+    if let Some(etag) = &cached.etag {request = request.header(IF_NONE_MATCH, etag)}
+    if let Some(last_modified) = &cached.last_modified {request = request.header(IF_MODIFIED_SINCE, last_modified)}
+  }
+  let response = request.send().map_err(|e| format!("Fetch error: {}", e))?;
+  if response.status() == StatusCode::NOT_MODIFIED {return Ok(Fetched::NotModified)}
+  let response = response.error_for_status().map_err(|e| format!("Fetch error: {}", e))?;
+  let header = |name| response.headers().get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(str::to_string);
+  let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+  let text = response.text().map_err(|e| format!("Fetch error: {}", e))?;
+  let signature = client.get(format!("{}.minisig", source)).send().and_then(|r| r.error_for_status()).and_then(|r| r.text()).ok();
+  Ok(Fetched::Modified {text, signature, etag, last_modified})
+}
+
+fn read_cache() -> Result<CacheEntry, String> {
+  let path = cache_file();
+  let text = read_to_string(&path).map_err(|e| format!("Cache error: {}: {}", path.display(), e))?;
+  let mut entry = CacheEntry::default();
+  let mut body = text.as_str();
+  while let Some((line, rest)) = body.split_once('\n').filter(|(line, _)| line.starts_with(CACHE_PREFIX)) {
+    let (key, value) = line[CACHE_PREFIX.len()..].split_once(' ').unwrap_or((&line[CACHE_PREFIX.len()..], ""));
+    match key {
+      "fetched" => entry.fetched = value.trim().parse().map_err(|_| format!("Cache error: {}: bad fetch time", path.display()))?,
+      "source" => entry.source = value.to_string(),
+      "etag" => entry.etag = Some(value.to_string()),
+      "last-modified" => entry.last_modified = Some(value.to_string()),
+      "failed" => entry.failed = value.split_once(' ').and_then(|(time, error)| Some((time.parse().ok()?, error.to_string()))),
+      _ => {}
+    }
+    body = rest;
+  }
+  if entry.fetched == 0 {return Err(format!("Cache error: {}: missing fetch time", path.display()))}
+  entry.text = body.to_string();
+  entry.signature = read_to_string(signature_file(&path)).ok();
+  Ok(entry)
+}
+
+fn write_cache(entry: &CacheEntry) -> Result<PathBuf, String> {
+  let path = cache_file();
+  let dir = path.parent().ok_or("Cache error: no cache directory")?;
+  create_dir_all(dir).map_err(|e| format!("Cache error: {}: {}", dir.display(), e))?;
+  let mut text = format!("{}fetched {}\n{}source {}\n", CACHE_PREFIX, entry.fetched, CACHE_PREFIX, entry.source);
+  if let Some(etag) = &entry.etag {text.push_str(&format!("{}etag {}\n", CACHE_PREFIX, etag))}
+  if let Some(last_modified) = &entry.last_modified {text.push_str(&format!("{}last-modified {}\n", CACHE_PREFIX, last_modified))}
+  if let Some((time, error)) = &entry.failed {text.push_str(&format!("{}failed {} {}\n", CACHE_PREFIX, time, error.replace('\n', " ")))}
+  text.push_str(&entry.text);
+  let partial = path.with_extension("partial");
+  write(&partial, text).map_err(|e| format!("Cache error: {}: {}", partial.display(), e))?;
+  rename(&partial, &path).map_err(|e| format!("Cache error: {}: {}", path.display(), e))?;
+  match &entry.signature {
+    Some(signature) => write(signature_file(&path), signature),
+    None => remove_file(signature_file(&path)).or_else(|e| if e.kind() == std::io::ErrorKind::NotFound {Ok(())} else {Err(e)})
+  }.map_err(|e| format!("Cache error: {}: {}", signature_file(&path).display(), e))?;
+  Ok(path)
+}
+
+fn update_from(settings: &OnlineSettings, source: &str, cached: Option<&CacheEntry>) -> Result<(Ruleset, PathBuf), String> {
+  let entry = match fetch_source(source, cached, settings.timeout)? {
+    Fetched::Modified {text, signature, etag, last_modified} =>
+      CacheEntry {fetched: now(), source: source.to_string(), etag, last_modified, signature, failed: None, text},
+    Fetched::NotModified => CacheEntry {fetched: now(), failed: None, ..cached.cloned().ok_or("Fetch error: not modified, but nothing cached")?}
+  };
+  settings.verify(&entry.text, entry.signature.as_deref())?;
+  let (configs, rules) = parse_ruleset(&entry.text, &entry.source)?;
+  let path = write_cache(&entry)?;
+  Ok((Ruleset {configs, rules, source: entry.source, fetched: entry.fetched, stale: None}, path))
+}
+
+pub fn update(settings: &OnlineSettings) -> Result<(Ruleset, PathBuf), String> {
+  if settings.sources.is_empty() {return Err("No online sources configured, set online.sources".to_string())}
+  let cached = read_cache().ok();
+  let mut errors = Vec::new();
+  for source in &settings.sources {
+    match update_from(settings, source, cached.as_ref()) {
+      Ok(updated) => return Ok(updated),
+      Err(e) => errors.push(format!("{}: {}", source, e))
+    }
+  }
+  Err(errors.join("; "))
+}
+
+pub fn load(settings: &OnlineSettings) -> Result<Ruleset, String> {
+  let cached = read_cache().and_then(|entry| {
+    settings.verify(&entry.text, entry.signature.as_deref())?;
+    Ok((parse_ruleset(&entry.text, &entry.source)?, entry))
+  });
+  if let Ok(((configs, rules), entry)) = &cached {
+    let ruleset = |stale| Ruleset {configs: configs.clone(), rules: rules.clone(), source: entry.source.clone(), fetched: entry.fetched, stale};
+    if now().saturating_sub(entry.fetched) < settings.ttl {return Ok(ruleset(None))}
+    if let Some((attempted, e)) = entry.failed.as_ref().filter(|(attempted, _)| now().saturating_sub(*attempted) < settings.ttl.min(RETRY_AFTER)) {
+      return Ok(ruleset(Some(format!("{} (at {})", e, attempted))))
+    }
+  }
+  match (update(settings), cached) {
+    (Ok((ruleset, _)), _) => Ok(ruleset),
+    (Err(mut e), Ok(((configs, rules), mut entry))) => {
+      entry.failed = Some((now(), e.clone()));
+      if let Err(cache) = write_cache(&entry) {e = format!("{}; {}", e, cache)}
+      Ok(Ruleset {configs, rules, source: entry.source, fetched: entry.fetched, stale: Some(e)})
+    }
+    (Err(e), Err(_)) => Err(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+  use std::thread::{self, JoinHandle};
+  use super::*;
+  use crate::tests::temp_root;
+
+  const PUBLIC_KEY: &str = "RWQBAgMEBQYHCIqI4910CfGV/VLbLTy6XXLKZwm/HZQSG/N0iAG0D29c";
+  const OTHER_KEY: &str = "RWQLDA0ODxAREoE5dw6ofRdfVqNUZsNMfszLjYqRtO43ol32D1uPybOU";
+  const RULESET: &str = "5;10;100\n";
+  const SIGNATURE: &str = "untrusted comment: signature from polite test key
+RUQBAgMEBQYHCNl72B/5Ag80bbjt0O+/HgkcooZ9P+nrhbKjAsrXakaj0dUAaF2RIEj5j9ERRiXcywJUOKcnpWybWS4bKLbz8Aw=
+trusted comment: timestamp:1792000000\tfile:rules.conf
+4hThLMC+OY2RRezFjCWHgq/LK9IkiDqKZNtUiOXhyHiMXfDrjzH5HgS6K/AV86nbs9T6qYlpzntjuwU3HrxnAQ==
+";
+
+  fn settings(keys: &[&str], allow_unsigned: bool) -> OnlineSettings {
+    OnlineSettings {public_keys: keys.iter().map(|key| key.to_string()).collect(), allow_unsigned, ..OnlineSettings::default()}
+  }
+
+  #[test]
+  fn accepts_signature_from_pinned_key() {
+    assert_eq!(settings(&[PUBLIC_KEY], false).verify(RULESET, Some(SIGNATURE)), Ok(()));
+    assert_eq!(settings(&[OTHER_KEY, PUBLIC_KEY], false).verify(RULESET, Some(SIGNATURE)), Ok(()));
+  }
+
+  #[test]
+  fn rejects_bad_signatures() {
+    let mismatch = Err("Signature error: ruleset signature does not match any key in online.public_keys".to_string());
+    assert_eq!(settings(&[PUBLIC_KEY], false).verify("5;-20;-1000\n", Some(SIGNATURE)), mismatch);
+    assert_eq!(settings(&[OTHER_KEY], false).verify(RULESET, Some(SIGNATURE)), mismatch);
+    assert!(settings(&[PUBLIC_KEY], false).verify(RULESET, Some("untrusted comment: x\nnot base64\n"))
+      .is_err_and(|e| e.starts_with("Signature error: malformed signature")));
+  }
+
+  #[test]
+  fn requires_signature_unless_allowed() {
+    assert_eq!(settings(&[PUBLIC_KEY], false).verify(RULESET, None), Err("Signature error: ruleset is not signed".to_string()));
+    assert!(settings(&[], false).verify(RULESET, Some(SIGNATURE)).is_err_and(|e| e.contains("no online.public_keys")));
+    assert_eq!(settings(&[], true).verify(RULESET, None), Ok(()));
+    assert_eq!(settings(&[], true).verify(RULESET, Some(SIGNATURE)), Ok(()));
+    assert!(settings(&[PUBLIC_KEY], true).verify("tampered", Some(SIGNATURE)).is_err());
+  }
+
+  #[test]
+  fn validates_pinned_keys() {
+    let mut settings = OnlineSettings::default();
+    assert!(settings.update(&format!("public_keys = [\"{}\"]", PUBLIC_KEY).parse().unwrap()).is_ok());
+    assert!(settings.update(&"public_keys = [\"RWQ\"]".parse().unwrap()).is_err_and(|e| e.starts_with("online.public_keys: invalid key")));
+  }
+
+  #[test]
+  fn reads_signature_next_to_file_source() {
+    let root = temp_root("online-file");
+    let path = root.join("rules.conf");
+    write(&path, RULESET).unwrap();
+    write(root.join("rules.conf.minisig"), SIGNATURE).unwrap();
+    let Ok(Fetched::Modified {text, signature, ..}) = fetch_source(&format!("file://{}", path.display()), None, 5) else {panic!("expected a body")};
+    assert_eq!(settings(&[PUBLIC_KEY], false).verify(&text, signature.as_deref()), Ok(()));
+  }
+
+  fn serve(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/rules.conf", listener.local_addr().unwrap());
+    let server = thread::spawn(move || responses.into_iter().map(|response| {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut request = Vec::new();
+      let mut byte = [0u8];
+      while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {request.push(byte[0])}
+      stream.write_all(response.as_bytes()).unwrap();
+      String::from_utf8_lossy(&request).to_lowercase()
+    }).collect());
+    (url, server)
+  }
+
+  #[test]
+  fn fetches_with_conditional_get() {
+    let (url, server) = serve(vec![
+      "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sat, 17 Oct 2026 10:00:00 GMT\r\nContent-Length: 8\r\nConnection: close\r\n\r\n5;10;100",
+      "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+      "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n"
+    ]);
+    let Ok(Fetched::Modified {text, signature, etag, last_modified}) = fetch_source(&url, None, 5) else {panic!("expected a body")};
+    assert_eq!(text, "5;10;100");
+    assert_eq!(signature, None);
+    assert_eq!(etag.as_deref(), Some("\"v1\""));
+    assert_eq!(last_modified.as_deref(), Some("Sat, 17 Oct 2026 10:00:00 GMT"));
+    let cached = CacheEntry {fetched: 1, source: url.clone(), etag, last_modified, signature, failed: None, text};
+    assert!(matches!(fetch_source(&url, Some(&cached), 5), Ok(Fetched::NotModified)));
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("get /rules.conf ") && !requests[0].contains("if-none-match"));
+    assert!(requests[1].starts_with("get /rules.conf.minisig "));
+    assert!(requests[2].contains("if-none-match: \"v1\"\r\n"));
+    assert!(requests[2].contains("if-modified-since: sat, 17 oct 2026 10:00:00 gmt\r\n"));
+  }
+
+  #[test]
+  fn skips_conditional_headers_for_another_source() {
+    let (url, server) = serve(vec![
+      "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\n5;10;100",
+      "HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsigned"
+    ]);
+    let cached = CacheEntry {source: "http://mirror.invalid/rules.conf".to_string(), etag: Some("\"v1\"".to_string()), ..CacheEntry::default()};
+    let Ok(Fetched::Modified {signature, ..}) = fetch_source(&url, Some(&cached), 5) else {panic!("expected a body")};
+    assert_eq!(signature.as_deref(), Some("signed"));
+    assert!(!server.join().unwrap()[0].contains("if-none-match"));
+  }
+
+  #[test]
+  fn reports_http_errors() {
+    let (url, server) = serve(vec!["HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+    assert!(fetch_source(&url, None, 5).is_err_and(|e| e.starts_with("Fetch error:")));
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn backs_off_after_failed_refresh() {
+    let root = temp_root("online-retry");
+    std::env::set_var("XDG_CACHE_HOME", &root);
+    let (url, server) = serve(vec!["HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+    let settings = OnlineSettings {ttl: 60, sources: vec![url.clone()], ..settings(&[], true)};
+    write_cache(&CacheEntry {fetched: 1, source: url, text: RULESET.to_string(), ..CacheEntry::default()}).unwrap();
+    let first = load(&settings).unwrap();
+    assert_eq!(server.join().unwrap().len(), 1);
+    assert!(first.stale.as_ref().is_some_and(|e| e.contains("Fetch error:")));
+    assert_eq!(first.configs.len(), 1);
+    let attempted = read_cache().unwrap().failed.unwrap().0;
+    let second = load(&settings).unwrap();
+    assert_eq!(second.stale, Some(format!("{} (at {})", first.stale.unwrap(), attempted)));
+    let mut entry = read_cache().unwrap();
+    entry.failed = Some((attempted - RETRY_AFTER, "old".to_string()));
+    write_cache(&entry).unwrap();
+    assert!(load(&settings).unwrap().stale.is_some_and(|e| !e.starts_with("old")));
+    assert_ne!(read_cache().unwrap().failed.unwrap().1, "old");
+  }
+}
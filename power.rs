@@ -0,0 +1,253 @@
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::supervise::Policy;
+use crate::{check_range, NICENESS_RANGE, PoliteConfig};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BatteryAction {
+  #[default]
+  Run,
+  Pause,
+  Nice(i32)
+}
+
+impl std::str::FromStr for BatteryAction {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "run" => Ok(BatteryAction::Run),
+      "pause" => Ok(BatteryAction::Pause),
+      _ => {
+        let niceness = s.strip_prefix("nice:").and_then(|n| n.parse().ok())
+          .ok_or_else(|| format!("Unknown on_battery action {}, expected pause, nice:N or run", s))?;
+        check_range("on_battery niceness", niceness, NICENESS_RANGE)?;
+        Ok(BatteryAction::Nice(niceness))
+      }
+    }
+  }
+}
+
+impl std::fmt::Display for BatteryAction {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      BatteryAction::Run => f.write_str("run"),
+      BatteryAction::Pause => f.write_str("pause"),
+      BatteryAction::Nice(niceness) => write!(f, "nice:{}", niceness)
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PowerPolicy {
+  pub on_battery: BatteryAction,
+  pub min_charge: Option<u32>
+}
+
+impl PowerPolicy {
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    match key {
+      "on_battery" => self.on_battery = value.parse()?,
+      "min_charge" => self.min_charge = Some(value.parse().ok().filter(|c| *c <= 100).ok_or("min_charge must be a percentage between 0 and 100")?),
+      _ => return Err(format!("Unknown power setting {}", key))
+    }
+    Ok(())
+  }
+
+  pub fn settings(&self) -> Vec<(&'static str, String)> {
+    let mut settings = vec![("on_battery", self.on_battery.to_string())];
+    if let Some(min_charge) = self.min_charge {settings.push(("min_charge", min_charge.to_string()))}
+    settings
+  }
+}
+
+impl std::fmt::Display for PowerPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "on_battery={}", self.on_battery)?;
+    if let Some(min_charge) = self.min_charge {write!(f, ", min_charge={}%", min_charge)?}
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerState {
+  pub on_battery: bool,
+  pub charge: Option<u32>
+}
+
+pub fn default_root() -> PathBuf {
+  PathBuf::from(std::env::var("POLITE_POWER_SUPPLY_ROOT").unwrap_or_else(|_| "/sys/class/power_supply".to_string()))
+}
+
+fn read_attribute(supply: &Path, name: &str) -> Option<String> {
+  read_to_string(supply.join(name)).ok().map(|value| value.trim().to_string())
+}
+
+pub fn power_state(root: &Path) -> Result<PowerState, String> {
+  let entries = read_dir(root).map_err(|e| format!("Power error: {}: {}", root.display(), e))?;
+  let (mut mains, mut mains_online, mut discharging, mut charges) = (false, false, false, Vec::new());
+  for supply in entries.flatten().map(|entry| entry.path()) {
+    match read_attribute(&supply, "type").as_deref() {
+      Some("Mains") | Some("USB") => {
+        mains = true;
+        mains_online |= read_attribute(&supply, "online").as_deref() == Some("1");
+      }
+      Some("Battery") if read_attribute(&supply, "scope").as_deref() != Some("Device") => {
+        discharging |= read_attribute(&supply, "status").as_deref() == Some("Discharging");
+        if let Some(capacity) = read_attribute(&supply, "capacity").and_then(|c| c.parse::<u32>().ok()) {charges.push(capacity)}
+      }
+      _ => {}
+    }
+  }
+  let on_battery = if mains {!mains_online} else {discharging};
+  let charge = if charges.is_empty() {None} else {Some(charges.iter().sum::<u32>() / charges.len() as u32)};
+  Ok(PowerState {on_battery, charge})
+}
+
+pub struct PowerWatcher {
+  policy: PowerPolicy,
+  root: PathBuf,
+  state: Option<PowerState>,
+  checked: Option<Instant>
+}
+
+impl PowerWatcher {
+  pub fn new(policy: PowerPolicy, root: PathBuf) -> PowerWatcher {
+    PowerWatcher {policy, root, state: None, checked: None}
+  }
+
+  fn low(&self, state: &PowerState) -> bool {
+    state.on_battery && self.policy.min_charge.zip(state.charge).is_some_and(|(min_charge, charge)| charge < min_charge)
+  }
+
+  fn check(&mut self) {
+    let state = match power_state(&self.root) {
+      Ok(state) => state,
+      Err(e) => {
+        if self.state.is_some() || self.checked.is_none() {eprintln!("{}, assuming mains power", e)}
+        self.state = None;
+        return
+      }
+    };
+    let previous = self.state.replace(state);
+    let charge = state.charge.map_or(String::new(), |charge| format!(" at {}%", charge));
+    if previous.is_none_or(|previous| previous.on_battery != state.on_battery) {
+      if state.on_battery {eprintln!("On battery{}, applying on_battery={}", charge, self.policy.on_battery)}
+      else if previous.is_some() {eprintln!("On mains power{}, restoring the job", charge)}
+    }
+    if self.low(&state) && previous.is_none_or(|previous| !self.low(&previous)) {
+      eprintln!("Battery charge{} is below min_charge={}%, pausing the job", charge, self.policy.min_charge.unwrap_or_default());
+    }
+  }
+}
+
+impl Policy for PowerWatcher {
+  fn adjust(&mut self, config: &mut PoliteConfig) -> bool {
+    if self.checked.is_none_or(|checked| checked.elapsed() >= CHECK_INTERVAL) {
+      self.check();
+      self.checked = Some(Instant::now());
+    }
+    let Some(state) = self.state.filter(|state| state.on_battery) else {return false};
+    if let BatteryAction::Nice(niceness) = self.policy.on_battery {
+      config.niceness = Some(config.niceness.map_or(niceness, |current| current.max(niceness)))
+    }
+    self.low(&state) || self.policy.on_battery == BatteryAction::Pause
+  }
+
+  fn raises_niceness(&self, base: &PoliteConfig) -> bool {
+    matches!(self.policy.on_battery, BatteryAction::Nice(niceness) if niceness > base.niceness.unwrap_or(0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn supply(root: &Path, name: &str, attributes: &[(&str, &str)]) {
+    let path = root.join(name);
+    std::fs::create_dir_all(&path).unwrap();
+    for (attribute, value) in attributes {std::fs::write(path.join(attribute), format!("{}\n", value)).unwrap()}
+  }
+
+  fn battery(root: &Path, name: &str, status: &str, capacity: &str) {
+    supply(root, name, &[("type", "Battery"), ("status", status), ("capacity", capacity)]);
+  }
+
+  #[test]
+  fn follows_mains_and_usb_supplies() {
+    let root = crate::tests::temp_root("power-mains");
+    supply(&root, "AC", &[("type", "Mains"), ("online", "1")]);
+    battery(&root, "BAT0", "Discharging", "80");
+    assert_eq!(power_state(&root), Ok(PowerState {on_battery: false, charge: Some(80)}));
+    supply(&root, "AC", &[("online", "0")]);
+    assert_eq!(power_state(&root), Ok(PowerState {on_battery: true, charge: Some(80)}));
+    supply(&root, "ucsi-source-psy-USBC000:001", &[("type", "USB"), ("online", "1")]);
+    assert_eq!(power_state(&root), Ok(PowerState {on_battery: false, charge: Some(80)}));
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn falls_back_to_battery_status_without_mains() {
+    let root = crate::tests::temp_root("power-battery");
+    battery(&root, "BAT0", "Charging", "40");
+    battery(&root, "BAT1", "Full", "100");
+    assert_eq!(power_state(&root), Ok(PowerState {on_battery: false, charge: Some(70)}));
+    battery(&root, "BAT1", "Discharging", "95");
+    assert_eq!(power_state(&root), Ok(PowerState {on_battery: true, charge: Some(67)}));
+    battery(&root, "BAT0", "Discharging", "unknown");
+    assert_eq!(power_state(&root).unwrap().charge, Some(95));
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn ignores_device_batteries() {
+    let root = crate::tests::temp_root("power-device");
+    supply(&root, "hidpp_battery_0", &[("type", "Battery"), ("scope", "Device"), ("status", "Discharging"), ("capacity", "5")]);
+    supply(&root, "hid-mouse", &[("online", "1")]);
+    assert_eq!(power_state(&root), Ok(PowerState {on_battery: false, charge: None}));
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(power_state(&root).unwrap_err().starts_with("Power error: "));
+  }
+
+  #[test]
+  fn parses_battery_actions() {
+    assert_eq!("pause".parse(), Ok(BatteryAction::Pause));
+    assert_eq!("run".parse(), Ok(BatteryAction::Run));
+    assert_eq!("nice:15".parse(), Ok(BatteryAction::Nice(15)));
+    assert!("nice:25".parse::<BatteryAction>().is_err());
+    assert!("sleep".parse::<BatteryAction>().unwrap_err().starts_with("Unknown on_battery action"));
+    assert_eq!(BatteryAction::Nice(-3).to_string(), "nice:-3");
+    let mut policy = PowerPolicy::default();
+    assert!(policy.set("min_charge", "101").is_err());
+    policy.set("min_charge", "20").unwrap();
+    assert_eq!(policy.to_string(), "on_battery=run, min_charge=20%");
+  }
+
+  #[test]
+  fn renices_and_pauses_on_battery() {
+    let root = crate::tests::temp_root("power-watcher");
+    supply(&root, "AC", &[("type", "Mains"), ("online", "0")]);
+    battery(&root, "BAT0", "Discharging", "50");
+    let mut watcher = PowerWatcher::new(PowerPolicy {on_battery: BatteryAction::Nice(10), min_charge: Some(20)}, root.clone());
+    let mut config = PoliteConfig {niceness: Some(15), ..PoliteConfig::default()};
+    assert!(!watcher.adjust(&mut config));
+    assert_eq!(config.niceness, Some(15));
+    let mut config = PoliteConfig::default();
+    watcher.adjust(&mut config);
+    assert_eq!(config.niceness, Some(10));
+    battery(&root, "BAT0", "Discharging", "10");
+    watcher.check();
+    assert!(watcher.adjust(&mut PoliteConfig::default()));
+    supply(&root, "AC", &[("online", "1")]);
+    watcher.check();
+    let mut config = PoliteConfig::default();
+    assert!(!watcher.adjust(&mut config));
+    assert_eq!(config.niceness, None);
+    std::fs::remove_dir_all(&root).unwrap();
+    watcher.check();
+    assert!(!watcher.adjust(&mut PoliteConfig::default()));
+  }
+}
@@ -0,0 +1,222 @@
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::supervise::Policy;
+use crate::{IoClass, PoliteConfig};
+
+const RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptivePolicy {
+  pub cpu: Option<f64>,
+  pub memory: Option<f64>,
+  pub io: Option<f64>,
+  pub interval: u64,
+  pub steps: u32,
+  pub freeze: bool
+}
+
+impl Default for AdaptivePolicy {
+  fn default() -> Self {
+    AdaptivePolicy {cpu: None, memory: None, io: None, interval: 2, steps: 3, freeze: false}
+  }
+}
+
+impl AdaptivePolicy {
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    let threshold = |value: &str| value.parse::<f64>().ok().filter(|t| (0.0..=100.0).contains(t))
+      .ok_or_else(|| format!("adaptive.{} must be a percentage between 0 and 100", key));
+    match key {
+      "cpu" => self.cpu = Some(threshold(value)?),
+      "memory" => self.memory = Some(threshold(value)?),
+      "io" => self.io = Some(threshold(value)?),
+      "interval" => self.interval = value.parse().ok().filter(|i| *i > 0).ok_or("adaptive.interval must be a positive number of seconds")?,
+      "steps" => self.steps = value.parse().ok().filter(|s| (1..=10).contains(s)).ok_or("adaptive.steps must be between 1 and 10")?,
+      "freeze" => self.freeze = value.parse().map_err(|_| "adaptive.freeze must be true or false")?,
+      _ => return Err(format!("Unknown adaptive setting {}", key))
+    }
+    Ok(())
+  }
+
+  pub fn settings(&self) -> Vec<(&'static str, String)> {
+    let mut settings = Vec::new();
+    for (resource, threshold) in RESOURCES.iter().zip([self.cpu, self.memory, self.io]) {
+      if let Some(threshold) = threshold {settings.push((*resource, threshold.to_string()))}
+    }
+    settings.push(("interval", self.interval.to_string()));
+    settings.push(("steps", self.steps.to_string()));
+    settings.push(("freeze", self.freeze.to_string()));
+    settings
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.cpu.is_none() && self.memory.is_none() && self.io.is_none() {
+      return Err("adaptive needs a threshold for at least one of cpu, memory or io".to_string())
+    }
+    Ok(())
+  }
+
+  fn thresholds(&self) -> impl Iterator<Item = (&'static str, f64)> {
+    RESOURCES.into_iter().zip([self.cpu, self.memory, self.io]).filter_map(|(resource, threshold)| Some((resource, threshold?)))
+  }
+}
+
+impl std::fmt::Display for AdaptivePolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let thresholds: Vec<String> = self.thresholds().map(|(resource, threshold)| format!("{}>{}%", resource, threshold)).collect();
+    write!(f, "{} every {}s in {} steps{}", thresholds.join(","), self.interval, self.steps, if self.freeze {" then freeze"} else {""})
+  }
+}
+
+pub fn default_root() -> PathBuf {
+  PathBuf::from(std::env::var("POLITE_PRESSURE_ROOT").unwrap_or_else(|_| "/proc/pressure".to_string()))
+}
+
+fn some_avg10(root: &std::path::Path, resource: &str) -> Result<f64, String> {
+  let path = root.join(resource);
+  let text = read_to_string(&path).map_err(|e| format!("Pressure error: {}: {}", path.display(), e))?;
+  text.lines().filter_map(|line| line.strip_prefix("some ")).flat_map(str::split_whitespace)
+    .find_map(|field| field.strip_prefix("avg10=")).and_then(|avg| avg.parse().ok())
+    .ok_or_else(|| format!("Pressure error: {}: no some avg10", path.display()))
+}
+
+pub struct PressurePolicy {
+  policy: AdaptivePolicy,
+  root: PathBuf,
+  step: u32,
+  checked: Option<Instant>
+}
+
+impl PressurePolicy {
+  pub fn new(policy: AdaptivePolicy, root: PathBuf) -> PressurePolicy {
+    PressurePolicy {policy, root, step: 0, checked: None}
+  }
+
+  fn interval(&self) -> Duration {
+    Duration::from_secs(self.policy.interval)
+  }
+
+  fn check(&mut self) {
+    let mut over = Vec::new();
+    let mut clear = true;
+    for (resource, threshold) in self.policy.thresholds() {
+      match some_avg10(&self.root, resource) {
+        Ok(pressure) => {
+          if pressure > threshold {over.push(format!("{} {:.1}% > {}%", resource, pressure, threshold))}
+          if pressure >= threshold / 2.0 {clear = false}
+        }
+        Err(e) => {eprintln!("{}", e); clear = false}
+      }
+    }
+    let last = self.policy.steps + self.policy.freeze as u32;
+    if !over.is_empty() && self.step < last {
+      self.step += 1;
+      eprintln!("Pressure {}, backing off to step {}/{}", over.join(", "), self.step, last);
+    } else if clear && self.step > 0 {
+      self.step -= 1;
+      eprintln!("Pressure cleared, restoring to step {}/{}", self.step, last);
+    }
+  }
+}
+
+impl Policy for PressurePolicy {
+  fn adjust(&mut self, config: &mut PoliteConfig) -> bool {
+    if self.checked.is_none_or(|checked| checked.elapsed() >= self.interval()) {
+      self.checked = Some(Instant::now());
+      self.check();
+    }
+    let (step, steps) = (self.step.min(self.policy.steps), self.policy.steps);
+    if step == 0 {return false}
+    let niceness = config.niceness.get_or_insert(0);
+    *niceness += ((19 - *niceness).max(0) * step as i32 + steps as i32 - 1) / steps as i32;
+    if let Some(weight) = &mut config.cgroup.cpu_weight {*weight = (*weight >> step).max(1)}
+    if let Some(weight) = &mut config.cgroup.io_weight {*weight = (*weight >> step).max(1)}
+    if step == steps {config.io_class = IoClass::Idle}
+    self.step > steps
+  }
+
+  fn raises_niceness(&self, base: &PoliteConfig) -> bool {
+    base.niceness.is_none_or(|niceness| niceness < 19)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::Path;
+
+  fn write_pressure(root: &Path, resource: &str, some: f64) {
+    std::fs::write(root.join(resource), format!("some avg10={:.2} avg60=0.00 avg300=0.00 total=0\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n", some)).unwrap();
+  }
+
+  fn adjusted(policy: &mut PressurePolicy) -> (PoliteConfig, bool) {
+    let mut config = PoliteConfig::default();
+    config.cgroup.cpu_weight = Some(100);
+    let freeze = policy.adjust(&mut config);
+    (config, freeze)
+  }
+
+  #[test]
+  fn reads_some_avg10() {
+    let root = crate::tests::temp_root("pressure-read");
+    write_pressure(&root, "cpu", 12.5);
+    assert_eq!(some_avg10(&root, "cpu"), Ok(12.5));
+    std::fs::write(root.join("io"), "full avg10=3.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+    assert!(some_avg10(&root, "io").unwrap_err().contains("no some avg10"));
+    assert!(some_avg10(&root, "memory").is_err());
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn backs_off_and_restores_in_steps() {
+    let root = crate::tests::temp_root("pressure-steps");
+    let mut policy = PressurePolicy::new(AdaptivePolicy {cpu: Some(20.0), freeze: true, ..AdaptivePolicy::default()}, root.clone());
+    write_pressure(&root, "cpu", 5.0);
+    let (config, freeze) = adjusted(&mut policy);
+    assert_eq!((config.niceness, config.cgroup.cpu_weight, freeze), (None, Some(100), false));
+
+    write_pressure(&root, "cpu", 50.0);
+    policy.check();
+    let (config, _) = adjusted(&mut policy);
+    assert_eq!((config.niceness, config.cgroup.cpu_weight, config.io_class), (Some(7), Some(50), IoClass::None));
+    policy.check();
+    policy.check();
+    let (config, freeze) = adjusted(&mut policy);
+    assert_eq!((config.niceness, config.cgroup.cpu_weight, config.io_class, freeze), (Some(19), Some(12), IoClass::Idle, false));
+    policy.check();
+    policy.check();
+    assert_eq!(policy.step, 4);
+    assert!(adjusted(&mut policy).1);
+
+    write_pressure(&root, "cpu", 15.0);
+    policy.check();
+    assert_eq!(policy.step, 4);
+    write_pressure(&root, "cpu", 5.0);
+    policy.check();
+    assert!(!adjusted(&mut policy).1);
+    for _ in 0..5 {policy.check()}
+    assert_eq!(adjusted(&mut policy).0.niceness, None);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn keeps_niceness_above_the_base() {
+    let root = crate::tests::temp_root("pressure-base");
+    let mut policy = PressurePolicy::new(AdaptivePolicy {io: Some(10.0), steps: 2, ..AdaptivePolicy::default()}, root.clone());
+    write_pressure(&root, "io", 40.0);
+    let mut config = PoliteConfig {niceness: Some(10), ..PoliteConfig::default()};
+    policy.adjust(&mut config);
+    assert_eq!(config.niceness, Some(15));
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn treats_unreadable_pressure_as_not_clear() {
+    let root = crate::tests::temp_root("pressure-missing");
+    let mut policy = PressurePolicy::new(AdaptivePolicy {memory: Some(10.0), ..AdaptivePolicy::default()}, root.clone());
+    policy.step = 2;
+    policy.check();
+    assert_eq!(policy.step, 2);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string};
+use nix::unistd::Pid;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+  pub ppid: Pid,
+  pub pgid: Pid
+}
+
+fn numeric_entries(dir: &str) -> Vec<Pid> {
+  let Ok(entries) = read_dir(dir) else {return Vec::new()};
+  let mut pids: Vec<Pid> = entries.flatten()
+    .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse().ok()).map(Pid::from_raw)).collect();
+  pids.sort();
+  pids
+}
+
+pub fn pids() -> Vec<Pid> {
+  numeric_entries("/proc")
+}
+
+pub fn threads(pid: Pid) -> Vec<Pid> {
+  numeric_entries(&format!("/proc/{}/task", pid))
+}
+
+pub fn comm(pid: Pid) -> Option<String> {
+  read_to_string(format!("/proc/{}/comm", pid)).ok().map(|comm| comm.trim().to_string())
+}
+
+pub fn cmdline(pid: Pid) -> Option<Vec<String>> {
+  let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+  Some(cmdline.split(|byte| *byte == 0).filter(|arg| !arg.is_empty()).map(|arg| String::from_utf8_lossy(arg).into_owned()).collect())
+}
+
+pub fn environ(pid: Pid, key: &str) -> Option<String> {
+  let environ = std::fs::read(format!("/proc/{}/environ", pid)).ok()?;
+  environ.split(|byte| *byte == 0).find_map(|entry| entry.strip_prefix(key.as_bytes())?.strip_prefix(b"="))
+    .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+pub fn uid(pid: Pid) -> Option<u32> {
+  let status = read_to_string(format!("/proc/{}/status", pid)).ok()?;
+  status.lines().find_map(|line| line.strip_prefix("Uid:")).and_then(|uids| uids.split_whitespace().next()?.parse().ok())
+}
+
+pub fn capabilities(pid: Pid) -> Option<u64> {
+  let status = read_to_string(format!("/proc/{}/status", pid)).ok()?;
+  status.lines().find_map(|line| line.strip_prefix("CapEff:")).and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+}
+
+pub fn stat(pid: Pid) -> Result<Stat, String> {
+  let text = read_to_string(format!("/proc/{}/stat", pid)).map_err(|e| format!("PID {}: {}", pid, e))?;
+  let fields: Vec<&str> = text.rsplit_once(')').map_or(Vec::new(), |(_, rest)| rest.split_whitespace().collect());
+  let field = |index: usize| fields.get(index).and_then(|f| f.parse().ok()).map(Pid::from_raw)
+    .ok_or_else(|| format!("PID {}: malformed /proc/{}/stat", pid, pid));
+  Ok(Stat {ppid: field(1)?, pgid: field(2)?})
+}
+
+pub fn process_group(pgid: Pid) -> Vec<Pid> {
+  pids().into_iter().filter(|pid| stat(*pid).is_ok_and(|stat| stat.pgid == pgid)).collect()
+}
+
+pub fn tree(root: Pid) -> Vec<Pid> {
+  let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+  for pid in pids() {
+    if let Ok(stat) = stat(pid) {children.entry(stat.ppid).or_default().push(pid)}
+  }
+  let mut tree = vec![root];
+  let mut index = 0;
+  while let Some(pid) = tree.get(index).copied() {
+    tree.extend(children.get(&pid).into_iter().flatten());
+    index += 1;
+  }
+  tree
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+use glob::Pattern;
+use nix::unistd::{getppid, getuid, Pid, Uid, User};
+use regex::Regex;
+use toml::Table;
+use crate::procfs;
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+  pub name: String,
+  pub alias: String,
+  pub priority: i64,
+  pub source: String,
+  exe: Option<Pattern>,
+  path: Option<Pattern>,
+  argv: Option<Regex>,
+  parent: Option<Pattern>,
+  user: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct Process {
+  pub path: PathBuf,
+  pub argv: Vec<String>,
+  pub parent: String,
+  pub uid: u32,
+  pub user: String
+}
+
+fn user_name(uid: Uid) -> String {
+  User::from_uid(uid).ok().flatten().map_or_else(|| uid.to_string(), |user| user.name)
+}
+
+impl Process {
+  pub fn for_launch(path: &Path, argv: Vec<String>) -> Process {
+    let uid = getuid();
+    Process {path: path.to_path_buf(), argv, parent: procfs::comm(getppid()).unwrap_or_default(), uid: uid.as_raw(), user: user_name(uid)}
+  }
+
+  pub fn for_pid(pid: Pid) -> Result<Process, String> {
+    let path = std::fs::read_link(format!("/proc/{}/exe", pid)).map_err(|e| format!("PID {}: {}", pid, e))?;
+    let argv = procfs::cmdline(pid).unwrap_or_default();
+    let parent = procfs::stat(pid).ok().and_then(|stat| procfs::comm(stat.ppid)).unwrap_or_default();
+    let uid = procfs::uid(pid).ok_or_else(|| format!("PID {}: no owner", pid))?;
+    Ok(Process {path, argv, parent, uid, user: user_name(Uid::from_raw(uid))})
+  }
+
+  pub fn executable(&self) -> String {
+    self.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+  }
+}
+
+fn pattern(key: &str, value: &toml::Value) -> Result<Pattern, String> {
+  let value = value.as_str().ok_or_else(|| format!("{} must be a string", key))?;
+  Pattern::new(value).map_err(|e| format!("{}: invalid glob {}: {}", key, value, e))
+}
+
+impl Rule {
+  pub fn parse(table: &Table, index: usize, source: &str) -> Result<Rule, String> {
+    let mut rule = Rule {name: format!("rule {}", index + 1), alias: String::new(), priority: 0, source: source.to_string(),
+      exe: None, path: None, argv: None, parent: None, user: None};
+    for (key, value) in table {
+      match key.as_str() {
+        "name" => rule.name = value.as_str().ok_or("name must be a string")?.to_string(),
+        "alias" => rule.alias = match value {
+          toml::Value::String(alias) => crate::normalize_alias(alias)?,
+          toml::Value::Integer(alias) => alias.to_string(),
+          _ => return Err("alias must be a string or integer".to_string())
+        },
+        "priority" => rule.priority = value.as_integer().ok_or("priority must be an integer")?,
+        "exe" => rule.exe = Some(pattern(key, value)?),
+        "path" => rule.path = Some(pattern(key, value)?),
+        "parent" => rule.parent = Some(pattern(key, value)?),
+        "argv" => {
+          let regex = value.as_str().ok_or("argv must be a string")?;
+          rule.argv = Some(Regex::new(regex).map_err(|e| format!("argv: invalid regex: {}", e))?);
+        }
+        "user" => rule.user = Some(match value {
+          toml::Value::String(user) => user.clone(),
+          toml::Value::Integer(uid) => uid.to_string(),
+          _ => return Err("user must be a name or uid".to_string())
+        }),
+        _ => return Err(format!("Unknown key {}", key))
+      }
+    }
+    if rule.alias.is_empty() || rule.alias == "0" {return Err(format!("{}: needs an alias other than 0", rule.name))}
+    if rule.exe.is_none() && rule.path.is_none() && rule.argv.is_none() && rule.parent.is_none() && rule.user.is_none() {
+      return Err(format!("{}: needs at least one of exe, path, argv, parent or user", rule.name))
+    }
+    Ok(rule)
+  }
+
+  pub fn matches(&self, process: &Process) -> bool {
+    self.exe.as_ref().is_none_or(|exe| exe.matches(&process.executable()))
+      && self.path.as_ref().is_none_or(|path| path.matches_path(&process.path))
+      && self.argv.as_ref().is_none_or(|argv| argv.is_match(&process.argv.join(" ")))
+      && self.parent.as_ref().is_none_or(|parent| parent.matches(&process.parent))
+      && self.user.as_ref().is_none_or(|user| *user == process.user || *user == process.uid.to_string())
+  }
+}
+
+pub fn select<'a>(rules: impl IntoIterator<Item = &'a Rule>, process: &Process) -> Option<&'a Rule> {
+  let mut best: Option<&Rule> = None;
+  for rule in rules.into_iter().filter(|rule| rule.matches(process)) {
+    if best.is_none_or(|best| rule.priority > best.priority) {best = Some(rule)}
+  }
+  best
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::{parse_toml_config, LoadedConfig};
+
+  fn rule(text: &str, index: usize, source: &str) -> Result<Rule, String> {
+    Rule::parse(&text.parse::<Table>().unwrap(), index, source)
+  }
+
+  fn process(path: &str, argv: &[&str], parent: &str, uid: u32, user: &str) -> Process {
+    Process {path: PathBuf::from(path), argv: argv.iter().map(|arg| arg.to_string()).collect(), parent: parent.to_string(), uid, user: user.to_string()}
+  }
+
+  fn ffmpeg() -> Process {
+    process("/usr/bin/ffmpeg", &["ffmpeg", "-i", "in.mkv", "out.webm"], "bash", 1000, "alice")
+  }
+
+  #[test]
+  fn validates_rules() {
+    let parsed = rule("alias = \"enc\"\nexe = \"ffmpeg\"", 2, "local.toml").unwrap();
+    assert_eq!((parsed.name.as_str(), parsed.alias.as_str(), parsed.priority, parsed.source.as_str()), ("rule 3", "enc", 0, "local.toml"));
+    assert_eq!(rule("alias = 7\nuser = 1000\nname = \"mine\"", 0, "").unwrap().alias, "7");
+    assert_eq!(rule("exe = \"x\"", 0, "").unwrap_err(), "rule 1: needs an alias other than 0");
+    assert_eq!(rule("alias = 0\nexe = \"x\"", 0, "").unwrap_err(), "rule 1: needs an alias other than 0");
+    assert_eq!(rule("alias = \"enc\"\npriority = 5", 1, "").unwrap_err(), "rule 2: needs at least one of exe, path, argv, parent or user");
+    assert!(rule("alias = \"enc\"\nexe = \"[\"", 0, "").unwrap_err().starts_with("exe: invalid glob"));
+    assert!(rule("alias = \"enc\"\nargv = \"(\"", 0, "").unwrap_err().starts_with("argv: invalid regex"));
+    assert_eq!(rule("alias = \"enc\"\nexe = 3", 0, "").unwrap_err(), "exe must be a string");
+    assert_eq!(rule("alias = \"enc\"\nuser = true", 0, "").unwrap_err(), "user must be a name or uid");
+    assert_eq!(rule("alias = \"enc\"\npriority = \"high\"", 0, "").unwrap_err(), "priority must be an integer");
+    assert_eq!(rule("alias = \"enc\"\ncomm = \"x\"", 0, "").unwrap_err(), "Unknown key comm");
+    assert!(rule("alias = \"bad alias\"\nexe = \"x\"", 0, "").is_err());
+  }
+
+  #[test]
+  fn matches_each_field() {
+    let matches = |text: &str| rule(&format!("alias = \"enc\"\n{}", text), 0, "").unwrap().matches(&ffmpeg());
+    assert!(matches("exe = \"ff*\""));
+    assert!(!matches("exe = \"ffprobe\""));
+    assert!(matches("path = \"/usr/*/ffmpeg\""));
+    assert!(!matches("path = \"/opt/**\""));
+    assert!(matches("argv = '-i \\S+\\.mkv'"));
+    assert!(!matches("argv = '^ffmpeg -y'"));
+    assert!(matches("parent = \"*sh\""));
+    assert!(!matches("parent = \"make\""));
+    assert!(matches("user = \"alice\""));
+    assert!(matches("user = 1000"));
+    assert!(matches("user = \"1000\""));
+    assert!(!matches("user = \"bob\""));
+    assert!(matches("exe = \"ffmpeg\"\nuser = \"alice\"\nparent = \"bash\""));
+    assert!(!matches("exe = \"ffmpeg\"\nuser = \"root\""));
+  }
+
+  #[test]
+  fn selects_by_priority_then_order() {
+    let low = rule("name = \"low\"\nalias = \"a\"\nexe = \"ffmpeg\"", 0, "").unwrap();
+    let first = rule("name = \"first\"\nalias = \"b\"\nexe = \"ff*\"\npriority = 5", 1, "").unwrap();
+    let second = rule("name = \"second\"\nalias = \"c\"\nuser = \"alice\"\npriority = 5", 2, "").unwrap();
+    let other = rule("name = \"other\"\nalias = \"d\"\nexe = \"x264\"\npriority = 10", 3, "").unwrap();
+    let rules = [low, first, second, other];
+    assert_eq!(select(&rules, &ffmpeg()).map(|rule| rule.name.as_str()), Some("first"));
+    assert_eq!(select(&rules[..1], &ffmpeg()).map(|rule| rule.name.as_str()), Some("low"));
+    assert_eq!(select(&rules[3..], &ffmpeg()).map(|rule| rule.name.as_str()), None);
+    assert_eq!(select(&rules, &process("/bin/sh", &[], "init", 0, "root")).map(|rule| rule.name.as_str()), None);
+  }
+
+  #[test]
+  fn prefers_later_layers_then_local_rules() {
+    let mut loaded = LoadedConfig::default();
+    parse_toml_config("[[rule]]\nname = \"system\"\nalias = \"a\"\nexe = \"ffmpeg\"", "/etc/polite/polite.toml", &mut loaded).unwrap();
+    parse_toml_config("[[rule]]\nname = \"user\"\nalias = \"b\"\nexe = \"ffmpeg\"", "~/.config/polite/polite.toml", &mut loaded).unwrap();
+    let names: Vec<&str> = loaded.rules.iter().map(|rule| rule.name.as_str()).collect();
+    assert_eq!(names, ["user", "system"]);
+    let online = [rule("name = \"online\"\nalias = \"c\"\nexe = \"ffmpeg\"", 0, "https://example.org/rules").unwrap()];
+    assert_eq!(select(loaded.rules.iter().chain(&online), &ffmpeg()).map(|rule| rule.name.as_str()), Some("user"));
+    let online = [rule("name = \"online\"\nalias = \"c\"\nexe = \"ffmpeg\"\npriority = 1", 0, "https://example.org/rules").unwrap()];
+    assert_eq!(select(loaded.rules.iter().chain(&online), &ffmpeg()).map(|rule| rule.name.as_str()), Some("online"));
+  }
+}
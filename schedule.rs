@@ -0,0 +1,249 @@
+use std::time::{Duration, Instant};
+use nix::libc;
+use toml::{Table, Value};
+use crate::supervise::Policy;
+use crate::{config, inherit_settings, PoliteConfig};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const EVERY_DAY: u8 = 0x7f;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+  pub name: String,
+  from: u32,
+  to: u32,
+  days: u8,
+  pub config: Box<PoliteConfig>
+}
+
+fn parse_time(key: &str, value: Option<&Value>) -> Result<u32, String> {
+  let value = value.and_then(Value::as_str).ok_or_else(|| format!("{} must be a time like \"06:30\"", key))?;
+  let (hours, minutes) = value.split_once(':').ok_or_else(|| format!("{}: invalid time {}, expected HH:MM", key, value))?;
+  match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+    (Ok(hours), Ok(minutes)) if hours <= 24 && minutes < 60 && hours * 60 + minutes <= 24 * 60 => Ok(hours * 60 + minutes),
+    _ => Err(format!("{}: invalid time {}, expected HH:MM", key, value))
+  }
+}
+
+fn parse_days(value: &Value) -> Result<u8, String> {
+  let days = value.as_array().ok_or("days must be an array of day names")?;
+  let mut mask = 0;
+  for day in days {
+    mask |= match day.as_str().map(str::to_lowercase).as_deref() {
+      Some("weekdays") => 0x3e,
+      Some("weekend") => 0x41,
+      Some(name) => DAYS.iter().position(|day| name.starts_with(day)).map(|index| 1 << index)
+        .ok_or_else(|| format!("days: unknown day {}", name))?,
+      None => return Err("days must be an array of day names".to_string())
+    };
+  }
+  Ok(mask)
+}
+
+fn format_time(minutes: u32) -> String {
+  format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl Window {
+  pub fn parse(block: &Table, index: usize, base: &Table) -> Result<Window, String> {
+    let name = match block.get("name") {
+      Some(name) => name.as_str().ok_or("name must be a string")?.to_string(),
+      None => format!("window {}", index + 1)
+    };
+    let from = parse_time("from", block.get("from")).map_err(|e| format!("{}: {}", name, e))?;
+    let to = parse_time("to", block.get("to")).map_err(|e| format!("{}: {}", name, e))?;
+    let days = block.get("days").map_or(Ok(EVERY_DAY), parse_days).map_err(|e| format!("{}: {}", name, e))?;
+    let mut merged: Table = base.iter().filter(|(key, _)| *key != "schedule").map(|(k, v)| (k.clone(), v.clone())).collect();
+    for (key, value) in block.iter().filter(|(key, _)| !["name", "from", "to", "days"].contains(&key.as_str())) {
+      merged.insert(key.clone(), value.clone());
+    }
+    let config = config::parse_alias_table(&merged).map_err(|e| format!("{}: {}", name, e))?;
+    Ok(Window {name, from, to, days, config: Box::new(config)})
+  }
+
+  pub fn contains(&self, weekday: u32, minute: u32) -> bool {
+    let on = |day: u32| self.days & (1 << (day % 7)) != 0;
+    if self.from <= self.to {
+      on(weekday) && (self.from..self.to).contains(&minute)
+    } else if minute >= self.from {
+      on(weekday)
+    } else {
+      minute < self.to && on(weekday + 6)
+    }
+  }
+}
+
+impl std::fmt::Display for Window {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{} ({}-{}", self.name, format_time(self.from), format_time(self.to))?;
+    if self.days != EVERY_DAY {
+      let days: Vec<&str> = DAYS.iter().enumerate().filter(|(index, _)| self.days & (1 << index) != 0).map(|(_, day)| *day).collect();
+      write!(f, " {}", days.join(","))?;
+    }
+    f.write_str(")")
+  }
+}
+
+pub fn parse_windows(value: &Value, base: &Table) -> Result<Vec<Window>, String> {
+  let blocks = value.as_array().ok_or("schedule must be an array of tables, use [[alias.NAME.schedule]]")?;
+  blocks.iter().enumerate().map(|(index, block)| {
+    let block = block.as_table().ok_or_else(|| format!("schedule {} must be a table", index + 1))?;
+    Window::parse(block, index, base).map_err(|e| format!("schedule: {}", e))
+  }).collect()
+}
+
+fn local_now() -> (u32, u32) {
+  let now = unsafe {libc::time(std::ptr::null_mut())};
+  let mut tm: libc::tm = unsafe {std::mem::zeroed()};
+  if unsafe {libc::localtime_r(&now, &mut tm)}.is_null() {return (0, 0)}
+  (tm.tm_wday as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+pub fn active(config: &PoliteConfig) -> Option<&Window> {
+  let (weekday, minute) = local_now();
+  config.schedule.iter().find(|window| window.contains(weekday, minute))
+}
+
+pub fn current(config: &PoliteConfig) -> &PoliteConfig {
+  active(config).map_or(config, |window| &window.config)
+}
+
+pub struct SchedulePolicy {
+  windows: Vec<Window>,
+  active: Option<usize>,
+  checked: Option<Instant>
+}
+
+impl SchedulePolicy {
+  pub fn new(windows: Vec<Window>) -> SchedulePolicy {
+    SchedulePolicy {windows, active: None, checked: None}
+  }
+}
+
+impl Policy for SchedulePolicy {
+  fn adjust(&mut self, config: &mut PoliteConfig) -> bool {
+    if self.checked.is_none_or(|checked| checked.elapsed() >= CHECK_INTERVAL) {
+      self.checked = Some(Instant::now());
+      let (weekday, minute) = local_now();
+      let active = self.windows.iter().position(|window| window.contains(weekday, minute));
+      if active != self.active {
+        match active {
+          Some(index) => eprintln!("Entering schedule window {}", self.windows[index]),
+          None => eprintln!("Leaving schedule window {}", self.active.map_or(String::new(), |index| self.windows[index].to_string()))
+        }
+        self.active = active;
+      }
+    }
+    if let Some(index) = self.active {
+      let mut window = (*self.windows[index].config).clone();
+      inherit_settings(&mut window, config);
+      *config = window;
+    }
+    false
+  }
+
+  fn raises_niceness(&self, base: &PoliteConfig) -> bool {
+    self.windows.iter().filter_map(|window| window.config.niceness).any(|niceness| niceness > base.niceness.unwrap_or(0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn window(block: &str) -> Window {
+    Window::parse(&block.parse::<Table>().unwrap(), 0, &Table::new()).unwrap()
+  }
+
+  #[test]
+  fn parses_times() {
+    let time = |value: &str| parse_time("from", Some(&Value::String(value.to_string())));
+    assert_eq!(time("06:30"), Ok(390));
+    assert_eq!(time("00:00"), Ok(0));
+    assert_eq!(time("24:00"), Ok(1440));
+    for invalid in ["24:01", "12:60", "noon", "12", "-1:00"] {assert!(time(invalid).is_err(), "{}", invalid)}
+    assert!(parse_time("to", Some(&Value::Integer(6))).is_err());
+    assert!(parse_time("to", None).unwrap_err().starts_with("to must be"));
+  }
+
+  #[test]
+  fn parses_days() {
+    let days = |value: &str| parse_days(&format!("days = {}", value).parse::<Table>().unwrap()["days"]);
+    assert_eq!(days(r#"["weekdays"]"#), Ok(0x3e));
+    assert_eq!(days(r#"["weekend"]"#), Ok(0x41));
+    assert_eq!(days(r#"["Monday", "wed", "SAT"]"#), Ok(0x4a));
+    assert_eq!(days("[]"), Ok(0));
+    assert!(days(r#"["someday"]"#).unwrap_err().contains("unknown day"));
+    assert!(days(r#""mon""#).is_err());
+    assert!(days("[1]").is_err());
+  }
+
+  #[test]
+  fn contains_same_day_windows() {
+    let work = window(r#"from = "09:00"
+to = "17:30"
+days = ["weekdays"]"#);
+    assert!(work.contains(1, 9 * 60));
+    assert!(work.contains(5, 17 * 60 + 29));
+    assert!(!work.contains(5, 17 * 60 + 30));
+    assert!(!work.contains(1, 8 * 60 + 59));
+    assert!(!work.contains(0, 12 * 60));
+    assert!(!work.contains(6, 12 * 60));
+  }
+
+  #[test]
+  fn contains_windows_across_midnight() {
+    let night = window(r#"from = "22:00"
+to = "06:00"
+days = ["fri", "sat"]"#);
+    assert!(night.contains(5, 22 * 60));
+    assert!(night.contains(5, 23 * 60 + 59));
+    assert!(night.contains(6, 0));
+    assert!(night.contains(6, 5 * 60 + 59));
+    assert!(!night.contains(6, 6 * 60));
+    assert!(night.contains(6, 23 * 60));
+    assert!(night.contains(0, 3 * 60));
+    assert!(!night.contains(0, 22 * 60));
+    assert!(!night.contains(5, 3 * 60));
+    assert!(!night.contains(1, 3 * 60));
+    let weekend = window(r#"from = "20:00"
+to = "02:00"
+days = ["sun"]"#);
+    assert!(weekend.contains(0, 21 * 60));
+    assert!(weekend.contains(1, 60));
+    assert!(!weekend.contains(0, 60));
+  }
+
+  #[test]
+  fn contains_whole_days() {
+    let all_day = window(r#"from = "00:00"
+to = "24:00""#);
+    assert!((0..7).all(|day| all_day.contains(day, 0) && all_day.contains(day, 1439)));
+    let empty = window(r#"from = "10:00"
+to = "10:00""#);
+    assert!(!empty.contains(3, 600));
+  }
+
+  #[test]
+  fn parses_and_displays_windows() {
+    let base: Table = "niceness = 5\noom_score_adj = 100\n".parse().unwrap();
+    let block: Table = "name = \"night\"\nfrom = \"22:00\"\nto = \"6:05\"\ndays = [\"weekend\"]\nniceness = 15\n".parse().unwrap();
+    let night = Window::parse(&block, 2, &base).unwrap();
+    assert_eq!((night.config.niceness, night.config.oom_score_adj), (Some(15), Some(100)));
+    assert_eq!(night.to_string(), "night (22:00-06:05 sun,sat)");
+    let block: Table = "from = \"22:00\"\nto = \"23:00\"\nbogus = 1\n".parse().unwrap();
+    assert!(Window::parse(&block, 2, &base).unwrap_err().starts_with("window 3: "));
+    assert_eq!(window("from = \"01:00\"\nto = \"02:00\"").to_string(), "window 1 (01:00-02:00)");
+  }
+
+  #[test]
+  fn active_window_keeps_settings_it_leaves_unset() {
+    let mut policy = SchedulePolicy::new(vec![window("from = \"00:00\"\nto = \"24:00\"\nniceness = 10")]);
+    policy.active = Some(0);
+    policy.checked = Some(Instant::now());
+    let mut config = PoliteConfig {niceness: Some(3), oom_score_adj: Some(200), ..PoliteConfig::default()};
+    assert!(!policy.adjust(&mut config));
+    assert_eq!((config.niceness, config.oom_score_adj), (Some(10), Some(200)));
+  }
+}
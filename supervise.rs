@@ -0,0 +1,187 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use nix::errno::Errno;
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::resource::{getrlimit, Resource};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{getpid, Pid};
+use crate::cgroup::Cgroup;
+use crate::idle::{self, IdleWatcher};
+use crate::power::{self, PowerWatcher};
+use crate::pressure::{self, PressurePolicy};
+use crate::schedule::SchedulePolicy;
+use crate::{procfs, apply_runtime_settings, get_applied_settings, inherit_settings, job_stopped, set_io_priority, IoClass, PoliteConfig};
+
+const TICK: Duration = Duration::from_millis(200);
+const CAP_SYS_NICE: u32 = 23;
+
+pub static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+pub trait Policy {
+  fn adjust(&mut self, config: &mut PoliteConfig) -> bool;
+
+  fn raises_niceness(&self, _base: &PoliteConfig) -> bool {
+    false
+  }
+}
+
+pub fn policies(config: &PoliteConfig) -> Vec<Box<dyn Policy>> {
+  let mut policies: Vec<Box<dyn Policy>> = Vec::new();
+  if !config.schedule.is_empty() {policies.push(Box::new(SchedulePolicy::new(config.schedule.clone())))}
+  if let Some(adaptive) = &config.adaptive {policies.push(Box::new(PressurePolicy::new(adaptive.clone(), pressure::default_root())))}
+  if let Some(policy) = &config.idle {policies.push(Box::new(IdleWatcher::new(policy, idle::source(&policy.source))))}
+  if let Some(policy) = &config.power {policies.push(Box::new(PowerWatcher::new(policy.clone(), power::default_root())))}
+  policies
+}
+
+struct Job<'a> {
+  pgid: Pid,
+  cgroup: Option<&'a Cgroup>,
+  applied: PoliteConfig,
+  failing: Vec<&'static str>
+}
+
+impl Job<'_> {
+  fn members(&self) -> Vec<Pid> {
+    match self.cgroup {
+      Some(cgroup) => cgroup.procs(),
+      None => procfs::process_group(self.pgid)
+    }
+  }
+
+  fn each_thread(&self, apply: impl Fn(Pid) -> Result<(), String>) -> Result<(), String> {
+    let mut result = Ok(());
+    for pid in self.members() {
+      for tid in procfs::threads(pid) {
+        if let Err(e) = apply(tid) {result = Err(format!("PID {} TID {}: {}", pid, tid, e))}
+      }
+    }
+    result
+  }
+
+  fn record(&mut self, setting: &'static str, result: Result<(), String>, update: impl FnOnce(&mut PoliteConfig)) {
+    match result {
+      Ok(()) => {
+        update(&mut self.applied);
+        self.failing.retain(|failing| *failing != setting);
+      }
+      Err(e) if !self.failing.contains(&setting) => {
+        eprintln!("{}, retrying", e);
+        self.failing.push(setting);
+      }
+      Err(_) => {}
+    }
+  }
+
+  fn apply(&mut self, config: &PoliteConfig) {
+    if let Some(cgroup) = self.cgroup.filter(|_| config.cgroup != self.applied.cgroup) {
+      let result = cgroup.set_limits(&config.cgroup);
+      self.record("cgroup", result, |applied| applied.cgroup = config.cgroup.clone());
+    }
+    if config.niceness != self.applied.niceness {
+      let setting = PoliteConfig {niceness: config.niceness, ..PoliteConfig::default()};
+      let result = self.each_thread(|tid| apply_runtime_settings(tid, &setting));
+      self.record("niceness", result, |applied| applied.niceness = config.niceness);
+    }
+    if (config.sched_policy, config.sched_reset_on_fork) != (self.applied.sched_policy, self.applied.sched_reset_on_fork) {
+      let setting = PoliteConfig {sched_policy: config.sched_policy, sched_reset_on_fork: config.sched_reset_on_fork, ..PoliteConfig::default()};
+      let result = self.each_thread(|tid| apply_runtime_settings(tid, &setting));
+      self.record("sched_policy", result, |applied| (applied.sched_policy, applied.sched_reset_on_fork) = (config.sched_policy, config.sched_reset_on_fork));
+    }
+    if config.oom_score_adj != self.applied.oom_score_adj {
+      let setting = PoliteConfig {oom_score_adj: config.oom_score_adj, ..PoliteConfig::default()};
+      let result = self.each_thread(|tid| apply_runtime_settings(tid, &setting));
+      self.record("oom_score_adj", result, |applied| applied.oom_score_adj = config.oom_score_adj);
+    }
+    if (config.io_class, config.io_level) != (self.applied.io_class, self.applied.io_level) {
+      let level = if config.io_class == IoClass::None {0} else {config.io_level};
+      let result = self.each_thread(|tid| set_io_priority(tid, config.io_class, level));
+      self.record("io_class", result, |applied| (applied.io_class, applied.io_level) = (config.io_class, config.io_level));
+    }
+    if config.cpus != self.applied.cpus {
+      let setting = PoliteConfig {cpus: config.cpus.clone(), ..PoliteConfig::default()};
+      let result = self.each_thread(|tid| apply_runtime_settings(tid, &setting));
+      self.record("cpus", result, |applied| applied.cpus = config.cpus.clone());
+    }
+    if config.rlimits != self.applied.rlimits {
+      let setting = PoliteConfig {rlimits: config.rlimits.clone(), ..PoliteConfig::default()};
+      let result = self.each_thread(|tid| apply_runtime_settings(tid, &setting));
+      self.record("rlimits", result, |applied| applied.rlimits = config.rlimits.clone());
+    }
+  }
+
+  fn pause(&self, paused: bool) {
+    let result = match self.cgroup {
+      Some(cgroup) => cgroup.freeze(paused),
+      None => killpg(self.pgid, if paused {Signal::SIGSTOP} else {Signal::SIGCONT}).map_err(|e| format!("Signal error: {}", e))
+    };
+    if let Err(e) = result {eprintln!("{}", e)}
+  }
+}
+
+fn can_lower_niceness(to: i32) -> bool {
+  procfs::capabilities(getpid()).is_some_and(|caps| caps & (1 << CAP_SYS_NICE) != 0)
+    || getrlimit(Resource::RLIMIT_NICE).is_ok_and(|(soft, _)| soft >= (20 - to) as u64)
+}
+
+pub fn supervise(child: Pid, base: &PoliteConfig, cgroup: Option<&Cgroup>, mut policies: Vec<Box<dyn Policy>>) -> Result<i32, String> {
+  let mut base = base.clone();
+  match get_applied_settings(getpid()) {
+    Ok(started) => inherit_settings(&mut base, &started),
+    Err(e) => eprintln!("{}, unset settings will not be restored", e)
+  }
+  let niceness = base.niceness.unwrap_or(0);
+  if policies.iter().any(|policy| policy.raises_niceness(&base)) && !can_lower_niceness(niceness) {
+    eprintln!("Without CAP_SYS_NICE or RLIMIT_NICE, niceness raised above {} stays raised until the job exits", niceness);
+  }
+  let mut job = Job {pgid: child, cgroup, applied: base.clone(), failing: Vec::new()};
+  let mut paused = false;
+  loop {
+    match waitpid(child, Some(WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED)) {
+      Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+      Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+      Ok(WaitStatus::Stopped(_, Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU)) => {job_stopped(child); paused = false}
+      Ok(_) | Err(Errno::EINTR) => {}
+      Err(e) => return Err(format!("Wait error: {}", e))
+    }
+    let mut config = base.clone();
+    let mut pause = false;
+    for policy in &mut policies {pause |= policy.adjust(&mut config)}
+    if TERMINATING.load(Ordering::SeqCst) {pause = false}
+    job.apply(&config);
+    if pause != paused {
+      job.pause(pause);
+      paused = pause;
+    }
+    std::thread::sleep(TICK);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_only_settings_that_were_applied() {
+    let mut job = Job {pgid: Pid::from_raw(1), cgroup: None, applied: PoliteConfig {niceness: Some(19), ..PoliteConfig::default()}, failing: Vec::new()};
+    job.record("niceness", Err("Niceness error: EACCES".to_string()), |applied| applied.niceness = Some(0));
+    job.record("niceness", Err("Niceness error: EACCES".to_string()), |applied| applied.niceness = Some(0));
+    assert_eq!((job.applied.niceness, job.failing.clone()), (Some(19), vec!["niceness"]));
+    job.record("oom_score_adj", Ok(()), |applied| applied.oom_score_adj = Some(500));
+    assert_eq!(job.applied.oom_score_adj, Some(500));
+    job.record("niceness", Ok(()), |applied| applied.niceness = Some(0));
+    assert_eq!((job.applied.niceness, job.failing.len()), (Some(0), 0));
+  }
+
+  #[test]
+  fn knows_which_policies_raise_niceness() {
+    let base = PoliteConfig {niceness: Some(10), ..PoliteConfig::default()};
+    let power = |on_battery| PowerWatcher::new(power::PowerPolicy {on_battery, min_charge: None}, std::path::PathBuf::new());
+    assert!(power(power::BatteryAction::Nice(15)).raises_niceness(&base));
+    assert!(!power(power::BatteryAction::Nice(5)).raises_niceness(&base));
+    assert!(!power(power::BatteryAction::Pause).raises_niceness(&base));
+    let pressure = PressurePolicy::new(pressure::AdaptivePolicy {cpu: Some(10.0), ..Default::default()}, std::path::PathBuf::new());
+    assert!(pressure.raises_niceness(&base));
+    assert!(!pressure.raises_niceness(&PoliteConfig {niceness: Some(19), ..PoliteConfig::default()}));
+  }
+}